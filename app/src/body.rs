@@ -0,0 +1,342 @@
+//! Viewers for captured request and response bodies.
+//!
+//! Bodies are stored the way they went over the wire, so they are decoded
+//! according to their `Content-Encoding` first and then shown based on their
+//! `Content-Type`: json as a collapsible tree, forms as a table, multipart parts
+//! one by one, images inline and anything else as text or a hex dump.
+use std::io::{self, Read};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use leptos::*;
+
+/// How much of a body is shown as text
+const MAX_BODY_LEN: usize = 100000;
+/// Decoded bodies can be much larger than what was captured, stop before they get silly
+const MAX_DECODED_LEN: u64 = 64 * 1024 * 1024;
+
+pub(crate) fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn read_limited(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    reader.take(MAX_DECODED_LEN).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Undo the content codings listed in a `Content-Encoding` header, which are
+/// listed in the order they were applied.
+pub fn decode_content(body: &[u8], content_encoding: &str) -> Result<Vec<u8>, String> {
+    let mut decoded = body.to_vec();
+    for coding in content_encoding.rsplit(',').map(str::trim) {
+        let failed = |e: io::Error| format!("could not decode {coding} body: {e}");
+        decoded = match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => decoded,
+            "gzip" | "x-gzip" => {
+                read_limited(flate2::read::MultiGzDecoder::new(&decoded[..])).map_err(failed)?
+            }
+            // Meant to be zlib wrapped, but plenty of servers send raw deflate
+            "deflate" => read_limited(flate2::read::ZlibDecoder::new(&decoded[..]))
+                .or_else(|_| read_limited(flate2::read::DeflateDecoder::new(&decoded[..])))
+                .map_err(failed)?,
+            "br" => read_limited(brotli_decompressor::Decompressor::new(&decoded[..], 4096))
+                .map_err(failed)?,
+            "zstd" => {
+                let decoder = ruzstd::StreamingDecoder::new(&decoded[..])
+                    .map_err(|e| format!("could not decode zstd body: {e}"))?;
+                read_limited(decoder).map_err(failed)?
+            }
+            other => return Err(format!("unsupported content encoding '{other}'")),
+        };
+    }
+    Ok(decoded)
+}
+
+/// Value of a parameter like `boundary` in a header such as `Content-Type`
+fn header_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1..i + 3].iter().all(u8::is_ascii_hexdigit) =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).expect("hex digits are ascii");
+                decoded.push(u8::from_str_radix(hex, 16).expect("checked to be hex digits"));
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Fields of an `application/x-www-form-urlencoded` body
+pub fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Split a `multipart/*` body into its parts, `None` when it is malformed
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Option<Vec<Part>> {
+    let delimiter = format!("--{boundary}");
+    let mut rest = &body[find(body, delimiter.as_bytes())? + delimiter.len()..];
+    let mut parts = vec![];
+    // Every part follows a delimiter, the last delimiter is followed by `--`
+    while !rest.starts_with(b"--") {
+        rest = rest.strip_prefix(b"\r\n")?;
+        let end = find(rest, format!("\r\n{delimiter}").as_bytes())?;
+        let part = &rest[..end];
+        let (head, body) = match part.strip_prefix(b"\r\n") {
+            // A part without headers starts with the empty line right away
+            Some(body) => (&part[..0], body),
+            None => {
+                let split = find(part, b"\r\n\r\n")?;
+                (&part[..split], &part[split + 4..])
+            }
+        };
+        let headers = String::from_utf8_lossy(head)
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+            .collect();
+        parts.push(Part {
+            headers,
+            body: body.to_vec(),
+        });
+        rest = &rest[end + 2 + delimiter.len()..];
+    }
+    Some(parts)
+}
+
+/// Classic hex dump with offsets and the printable characters on the side
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+                    true => byte as char,
+                    false => '.',
+                })
+                .collect();
+            format!("{:08x}  {:<47}  |{ascii}|", line * 16, hex.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn json_view(key: Option<String>, value: serde_json::Value) -> View {
+    use serde_json::Value;
+
+    let label = key.map(|key| format!("{key}: "));
+    let (summary, children) = match value {
+        Value::Object(map) => (
+            format!("{{{} keys}}", map.len()),
+            map.into_iter()
+                .map(|(key, value)| json_view(Some(key), value))
+                .collect_view(),
+        ),
+        Value::Array(items) => (
+            format!("[{} items]", items.len()),
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| json_view(Some(i.to_string()), value))
+                .collect_view(),
+        ),
+        scalar => {
+            return view! { <div class="pl-4 font-mono text-sm">{label} {scalar.to_string()}</div> }
+                .into_view()
+        }
+    };
+    view! {
+        <details open class="pl-4 font-mono text-sm">
+            <summary>{label} {summary}</summary>
+            {children}
+        </details>
+    }
+    .into_view()
+}
+
+fn text_view(bytes: &[u8]) -> View {
+    let truncated = bytes.len() > MAX_BODY_LEN;
+    let shown = &bytes[..bytes.len().min(MAX_BODY_LEN)];
+    let content = match std::str::from_utf8(shown) {
+        Ok(text) => text.to_owned(),
+        // Cutting off the body can split a character, that does not make it binary
+        Err(e) if truncated && e.error_len().is_none() => {
+            String::from_utf8_lossy(&shown[..e.valid_up_to()]).into_owned()
+        }
+        Err(_) => hex_dump(shown),
+    };
+    view! {
+        <pre class="text-sm whitespace-pre-wrap break-all">{content}</pre>
+        <span class:hidden=!truncated class="text-red-500">
+            "(body truncated)"
+        </span>
+    }
+    .into_view()
+}
+
+fn body_view(headers: &[(String, String)], body: Vec<u8>) -> View {
+    let encoding = header(headers, "content-encoding").unwrap_or_default();
+    let body = match decode_content(&body, encoding) {
+        Ok(body) => body,
+        Err(e) => {
+            return view! {
+                <p class="text-red-500">{e}</p>
+                {text_view(&body)}
+            }
+            .into_view()
+        }
+    };
+    let content_type = header(headers, "content-type").unwrap_or_default();
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    if mime.starts_with("image/") {
+        let src = format!("data:{mime};base64,{}", STANDARD.encode(&body));
+        return view! { <img class="max-w-full max-h-96" src=src/> }.into_view();
+    }
+    if mime == "application/x-www-form-urlencoded" {
+        let fields = parse_form(&String::from_utf8_lossy(&body));
+        return view! {
+            <table class="text-sm font-mono">
+                {fields
+                    .into_iter()
+                    .map(|(name, value)| view! { <tr><td class="pr-4 font-semibold">{name}</td><td>{value}</td></tr> })
+                    .collect_view()}
+            </table>
+        }
+        .into_view();
+    }
+    if mime.starts_with("multipart/") {
+        let parts = header_param(content_type, "boundary")
+            .and_then(|boundary| parse_multipart(&body, boundary));
+        if let Some(parts) = parts {
+            return parts
+                .into_iter()
+                .map(|part| {
+                    let disposition = header(&part.headers, "content-disposition")
+                        .unwrap_or_default()
+                        .to_owned();
+                    view! {
+                        <div class="border-l-2 pl-2 my-2">
+                            <p class="font-semibold text-sm">{disposition}</p>
+                            {body_view(&part.headers, part.body)}
+                        </div>
+                    }
+                })
+                .collect_view();
+        }
+    }
+    let looks_like_json = mime.ends_with("json")
+        || body
+            .iter()
+            .find(|byte| !byte.is_ascii_whitespace())
+            .is_some_and(|byte| matches!(byte, b'{' | b'['));
+    if looks_like_json {
+        if let Ok(value) = serde_json::from_slice(&body) {
+            return json_view(None, value);
+        }
+    }
+    text_view(&body)
+}
+
+/// A body, shown the way its headers say it should be understood
+#[component]
+pub fn BodyView(headers: Vec<(String, String)>, body: Vec<u8>) -> impl IntoView {
+    body_view(&headers, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn stacked_encodings_are_undone_in_reverse() {
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(b"hello").unwrap();
+        let gzipped = gzip.finish().unwrap();
+        let mut deflate = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        deflate.write_all(&gzipped).unwrap();
+        let body = deflate.finish().unwrap();
+
+        assert_eq!(decode_content(&body, "gzip, deflate").unwrap(), b"hello");
+        assert_eq!(decode_content(b"hello", "identity").unwrap(), b"hello");
+        assert!(decode_content(b"hello", "gzip").is_err());
+        assert!(decode_content(b"hello", "compress").is_err());
+    }
+
+    #[test]
+    fn forms_and_multipart_bodies_are_split_up() {
+        assert_eq!(
+            parse_form("name=storm+grok&path=%2Fhooks%2f&empty"),
+            vec![
+                ("name".into(), "storm grok".into()),
+                ("path".into(), "/hooks/".into()),
+                ("empty".into(), "".into()),
+            ]
+        );
+        let body = b"preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\none\r\n--xyz\r\n\r\ntwo\r\n--xyz--\r\n";
+        let parts = parse_multipart(body, "xyz").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            header(&parts[0].headers, "content-disposition"),
+            Some("form-data; name=\"a\"")
+        );
+        assert_eq!(parts[0].body, b"one");
+        assert_eq!(parts[1].body, b"two");
+        assert!(parse_multipart(b"--xyz\r\nunterminated", "xyz").is_none());
+    }
+
+    #[test]
+    fn hex_dumps_line_up() {
+        let dump = hex_dump(b"\x00grok\xff");
+        assert_eq!(
+            dump,
+            format!("00000000  {:<47}  |.grok.|", "00 67 72 6f 6b ff")
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Exchanges held up by the eaves proxies until the inspector decides on them.
+//!
+//! A proxy asks for a pause with the head it is about to pass on, and the body
+//! when it could hold that back too, and waits on the receiver it gets back.
+//! Exchanges nobody decides on within [`MAX_PAUSE`] go on unchanged, so a
+//! forgotten breakpoint does not keep a visitor waiting forever.
+use std::{collections::BTreeMap, time::Duration};
+
+use shared_types::{
+    intercept::{BreakRule, Paused, Phase, Verdict},
+    RequestHead, ResponseHead, TunnelPorts,
+};
+use tokio::sync::oneshot;
+
+/// How long an exchange is held at a breakpoint at most
+pub const MAX_PAUSE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub struct Breakpoints {
+    rules: Vec<BreakRule>,
+    paused: BTreeMap<u64, (Paused, oneshot::Sender<Verdict>)>,
+    next_id: u64,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Breakpoints {
+            rules: vec![],
+            paused: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl Breakpoints {
+    pub fn rules(&self) -> &[BreakRule] {
+        &self.rules
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<BreakRule>) {
+        self.rules = rules;
+    }
+
+    /// Whether a rule asks for exchanges with this request to be paused in this phase
+    pub fn wants(&self, phase: Phase, request_head: &RequestHead) -> bool {
+        self.rules.iter().any(|rule| {
+            let in_phase = match phase {
+                Phase::Request => rule.requests,
+                Phase::Response => rule.responses,
+            };
+            in_phase && rule.request.matches(request_head)
+        })
+    }
+
+    /// Hold up an exchange when a rule asks for it in this phase, the verdict
+    /// arrives on the returned receiver
+    pub fn pause(
+        &mut self,
+        phase: Phase,
+        request_head: &RequestHead,
+        request_body: Option<Vec<u8>>,
+        response_head: Option<&ResponseHead>,
+        ports: TunnelPorts,
+    ) -> Option<(u64, oneshot::Receiver<Verdict>)> {
+        if !self.wants(phase, request_head) {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let paused = Paused {
+            id,
+            phase,
+            request_head: request_head.clone(),
+            response_head: response_head.cloned(),
+            ports,
+            request_body,
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.paused.insert(id, (paused, sender));
+        Some((id, receiver))
+    }
+
+    /// The exchanges waiting for a verdict, oldest first
+    pub fn paused(&self) -> Vec<Paused> {
+        self.paused
+            .values()
+            .map(|(paused, _)| paused.clone())
+            .collect()
+    }
+
+    /// Pass the verdict on to a paused exchange, false when it is not waiting anymore
+    pub fn resolve(&mut self, id: u64, verdict: Verdict) -> bool {
+        self.paused
+            .remove(&id)
+            .is_some_and(|(_, sender)| sender.send(verdict).is_ok())
+    }
+
+    /// Stop holding an exchange that gave up waiting
+    pub fn forget(&mut self, id: u64) {
+        self.paused.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::filter::RequestMatch;
+
+    #[test]
+    fn only_matching_exchanges_are_paused() {
+        let head = RequestHead {
+            method: "POST".into(),
+            uri: "/hooks/github".into(),
+            headers: vec![],
+        };
+        let ports = TunnelPorts {
+            target_port: 3000,
+            proxy_port: 6190,
+            target_host: "127.0.0.1".into(),
+            target_tls: false,
+            insecure_target: false,
+        };
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.set_rules(vec![BreakRule {
+            request: RequestMatch {
+                path: Some("/hooks/*".into()),
+                ..RequestMatch::default()
+            },
+            requests: true,
+            responses: false,
+        }]);
+        assert!(breakpoints
+            .pause(Phase::Response, &head, None, None, ports.clone())
+            .is_none());
+
+        let (id, mut receiver) = breakpoints
+            .pause(Phase::Request, &head, Some(vec![]), None, ports)
+            .unwrap();
+        assert_eq!(breakpoints.paused().len(), 1);
+        assert!(breakpoints.resolve(id, Verdict::Drop));
+        assert!(matches!(receiver.try_recv(), Ok(Verdict::Drop)));
+        assert!(breakpoints.paused().is_empty());
+        assert!(!breakpoints.resolve(id, Verdict::Drop));
+    }
+}
@@ -0,0 +1,350 @@
+//! Side by side comparison of two captured request cycles.
+//!
+//! Heads and text bodies are compared line by line, keeping the order headers
+//! were sent in. When both bodies are json they are compared structurally, so
+//! reformatting or reordered keys do not show up as changes.
+use leptos::*;
+use serde_json::Value;
+use shared_types::RequestCycle;
+
+use crate::body::{decode_content, header};
+
+/// Above this many lines times lines the diff stops looking for the smallest change
+const MAX_DIFF_CELLS: usize = 1_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RowKind {
+    Same,
+    Changed,
+    Removed,
+    Added,
+}
+
+/// One line of a side by side diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub kind: RowKind,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+impl Row {
+    fn same(line: &str) -> Self {
+        Row {
+            kind: RowKind::Same,
+            left: Some(line.to_owned()),
+            right: Some(line.to_owned()),
+        }
+    }
+}
+
+/// Line by line diff based on the longest common subsequence. Lines removed
+/// right before lines were added are paired up as changed lines.
+pub fn diff_lines<S: AsRef<str>>(left: &[S], right: &[S]) -> Vec<Row> {
+    let prefix = left
+        .iter()
+        .zip(right)
+        .take_while(|(l, r)| l.as_ref() == r.as_ref())
+        .count();
+    let suffix = left[prefix..]
+        .iter()
+        .rev()
+        .zip(right[prefix..].iter().rev())
+        .take_while(|(l, r)| l.as_ref() == r.as_ref())
+        .count();
+    let (l, r) = (
+        &left[prefix..left.len() - suffix],
+        &right[prefix..right.len() - suffix],
+    );
+
+    // `common[i][j]` is the length of the longest common subsequence of `l[i..]` and `r[j..]`
+    let mut common = vec![vec![0u32; r.len() + 1]; l.len() + 1];
+    if l.len() * r.len() <= MAX_DIFF_CELLS {
+        for i in (0..l.len()).rev() {
+            for j in (0..r.len()).rev() {
+                common[i][j] = match l[i].as_ref() == r[j].as_ref() {
+                    true => common[i + 1][j + 1] + 1,
+                    false => common[i + 1][j].max(common[i][j + 1]),
+                };
+            }
+        }
+    }
+
+    let mut rows: Vec<Row> = left[..prefix]
+        .iter()
+        .map(|s| Row::same(s.as_ref()))
+        .collect();
+    let (mut removed, mut added) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < l.len() || j < r.len() {
+        if i < l.len() && j < r.len() && l[i].as_ref() == r[j].as_ref() {
+            flush_changes(&mut rows, &mut removed, &mut added);
+            rows.push(Row::same(l[i].as_ref()));
+            i += 1;
+            j += 1;
+        } else if j == r.len() || (i < l.len() && common[i + 1][j] >= common[i][j + 1]) {
+            removed.push(l[i].as_ref().to_owned());
+            i += 1;
+        } else {
+            added.push(r[j].as_ref().to_owned());
+            j += 1;
+        }
+    }
+    flush_changes(&mut rows, &mut removed, &mut added);
+    rows.extend(
+        left[left.len() - suffix..]
+            .iter()
+            .map(|s| Row::same(s.as_ref())),
+    );
+    rows
+}
+
+fn flush_changes(rows: &mut Vec<Row>, removed: &mut Vec<String>, added: &mut Vec<String>) {
+    let paired = removed.len().max(added.len());
+    let mut removed = removed.drain(..);
+    let mut added = added.drain(..);
+    for _ in 0..paired {
+        let (left, right) = (removed.next(), added.next());
+        let kind = match (&left, &right) {
+            (Some(_), Some(_)) => RowKind::Changed,
+            (Some(_), None) => RowKind::Removed,
+            _ => RowKind::Added,
+        };
+        rows.push(Row { kind, left, right });
+    }
+}
+
+/// The values that differ between two json documents, by path
+pub fn diff_json(left: &Value, right: &Value) -> Vec<Row> {
+    let mut rows = vec![];
+    diff_json_at("$", left, right, &mut rows);
+    rows
+}
+
+fn diff_json_at(path: &str, left: &Value, right: &Value, rows: &mut Vec<Row>) {
+    let entry = |value: &Value| Some(format!("{path}: {value}"));
+    match (left, right) {
+        (Value::Object(l), Value::Object(r)) => {
+            for (key, value) in l {
+                let path = format!("{path}.{key}");
+                match r.get(key) {
+                    Some(other) => diff_json_at(&path, value, other, rows),
+                    None => rows.push(Row {
+                        kind: RowKind::Removed,
+                        left: Some(format!("{path}: {value}")),
+                        right: None,
+                    }),
+                }
+            }
+            for (key, value) in r.iter().filter(|(key, _)| !l.contains_key(*key)) {
+                rows.push(Row {
+                    kind: RowKind::Added,
+                    left: None,
+                    right: Some(format!("{path}.{key}: {value}")),
+                });
+            }
+        }
+        (Value::Array(l), Value::Array(r)) => {
+            for i in 0..l.len().max(r.len()) {
+                let path = format!("{path}[{i}]");
+                match (l.get(i), r.get(i)) {
+                    (Some(l), Some(r)) => diff_json_at(&path, l, r, rows),
+                    (Some(l), None) => rows.push(Row {
+                        kind: RowKind::Removed,
+                        left: Some(format!("{path}: {l}")),
+                        right: None,
+                    }),
+                    (None, r) => rows.push(Row {
+                        kind: RowKind::Added,
+                        left: None,
+                        right: r.map(|r| format!("{path}: {r}")),
+                    }),
+                }
+            }
+        }
+        (l, r) if l != r => rows.push(Row {
+            kind: RowKind::Changed,
+            left: entry(l),
+            right: entry(r),
+        }),
+        _ => {}
+    }
+}
+
+fn header_lines(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}"))
+        .collect()
+}
+
+/// Compare two bodies after undoing their content encoding
+pub fn diff_bodies(
+    left: (&[(String, String)], &[u8]),
+    right: (&[(String, String)], &[u8]),
+) -> Result<Vec<Row>, String> {
+    let decode = |(headers, body): (&[(String, String)], &[u8])| -> Result<Vec<u8>, String> {
+        decode_content(
+            body,
+            header(headers, "content-encoding").unwrap_or_default(),
+        )
+    };
+    let (left, right) = (decode(left)?, decode(right)?);
+    if let (Ok(l), Ok(r)) = (
+        serde_json::from_slice::<Value>(&left),
+        serde_json::from_slice::<Value>(&right),
+    ) {
+        return Ok(diff_json(&l, &r));
+    }
+    match (std::str::from_utf8(&left), std::str::from_utf8(&right)) {
+        (Ok(l), Ok(r)) => Ok(diff_lines(
+            &l.lines().collect::<Vec<_>>(),
+            &r.lines().collect::<Vec<_>>(),
+        )),
+        _ if left == right => Ok(vec![]),
+        _ => Ok(vec![Row {
+            kind: RowKind::Changed,
+            left: Some(format!("{} bytes of binary data", left.len())),
+            right: Some(format!("{} bytes of binary data", right.len())),
+        }]),
+    }
+}
+
+#[component]
+fn DiffTable(title: &'static str, rows: Result<Vec<Row>, String>) -> impl IntoView {
+    let rows = match rows {
+        Ok(rows) if rows.iter().all(|row| row.kind == RowKind::Same) => {
+            return view! { <p class="text-sm">{title} ": no differences"</p> }.into_view()
+        }
+        Ok(rows) => rows,
+        Err(e) => {
+            return view! { <p class="text-sm text-red-500">{title} ": " {e}</p> }.into_view()
+        }
+    };
+    view! {
+        <p class="font-semibold">{title}</p>
+        <table class="w-full table-fixed font-mono text-sm">
+            {rows
+                .into_iter()
+                .map(|row| {
+                    let (left_class, right_class) = match row.kind {
+                        RowKind::Same => ("", ""),
+                        RowKind::Changed => ("bg-yellow-100", "bg-yellow-100"),
+                        RowKind::Removed => ("bg-red-100", ""),
+                        RowKind::Added => ("", "bg-green-100"),
+                    };
+                    view! {
+                        <tr>
+                            <td class=format!("break-all align-top {left_class}")>{row.left}</td>
+                            <td class=format!("break-all align-top {right_class}")>{row.right}</td>
+                        </tr>
+                    }
+                })
+                .collect_view()}
+        </table>
+    }
+    .into_view()
+}
+
+/// Two request cycles next to each other, the older one on the left
+#[component]
+pub fn DiffView(left: RequestCycle, right: RequestCycle) -> impl IntoView {
+    let request_line = |cycle: &RequestCycle| {
+        vec![format!(
+            "{} {}",
+            cycle.request_head.method, cycle.request_head.uri
+        )]
+    };
+    let status_line = |cycle: &RequestCycle| vec![cycle.response_head.status.to_string()];
+    let request_body = diff_bodies(
+        (&left.request_head.headers, &left.request_body),
+        (&right.request_head.headers, &right.request_body),
+    );
+    let response_body = diff_bodies(
+        (&left.response_head.headers, &left.response_body),
+        (&right.response_head.headers, &right.response_body),
+    );
+    view! {
+        <div class="border p-4 rounded-lg shadow space-y-2">
+            <div class="grid grid-cols-2 font-bold">
+                <p>{left.timestamp_in.to_string()}</p>
+                <p>{right.timestamp_in.to_string()}</p>
+            </div>
+            <DiffTable
+                title="Request line"
+                rows=Ok(diff_lines(&request_line(&left), &request_line(&right)))
+            />
+            <DiffTable
+                title="Request headers"
+                rows=Ok(
+                    diff_lines(
+                        &header_lines(&left.request_head.headers),
+                        &header_lines(&right.request_head.headers),
+                    ),
+                )
+            />
+            <DiffTable title="Request body" rows=request_body/>
+            <DiffTable
+                title="Status"
+                rows=Ok(diff_lines(&status_line(&left), &status_line(&right)))
+            />
+            <DiffTable
+                title="Response headers"
+                rows=Ok(
+                    diff_lines(
+                        &header_lines(&left.response_head.headers),
+                        &header_lines(&right.response_head.headers),
+                    ),
+                )
+            />
+            <DiffTable title="Response body" rows=response_body/>
+        </div>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(rows: &[Row]) -> Vec<RowKind> {
+        rows.iter().map(|row| row.kind).collect()
+    }
+
+    #[test]
+    fn lines_are_diffed_in_order() {
+        let left = ["host: a", "x-one: 1", "x-two: 2", "accept: */*"];
+        let right = ["host: a", "x-two: 2", "x-three: 3", "accept: */*"];
+        let rows = diff_lines(&left, &right);
+        use RowKind::*;
+        assert_eq!(kinds(&rows), vec![Same, Removed, Same, Added, Same]);
+        assert_eq!(rows[3].right.as_deref(), Some("x-three: 3"));
+
+        let rows = diff_lines(&["a", "b"], &["a", "c"]);
+        assert_eq!(kinds(&rows), vec![Same, Changed]);
+        assert!(diff_lines::<&str>(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn json_is_diffed_by_structure() {
+        let left = serde_json::json!({"action": "opened", "labels": ["bug"], "draft": false});
+        let right = serde_json::json!({"labels": ["bug", "wip"], "action": "closed", "number": 7});
+        let rows = diff_json(&left, &right);
+        let changes: Vec<_> = rows
+            .iter()
+            .map(|row| (row.kind, row.left.as_deref(), row.right.as_deref()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    RowKind::Changed,
+                    Some(r#"$.action: "opened""#),
+                    Some(r#"$.action: "closed""#)
+                ),
+                (RowKind::Removed, Some("$.draft: false"), None),
+                (RowKind::Added, None, Some(r#"$.labels[1]: "wip""#)),
+                (RowKind::Added, None, Some("$.number: 7")),
+            ]
+        );
+    }
+}
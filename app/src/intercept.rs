@@ -0,0 +1,395 @@
+//! Breakpoints in the inspector: which exchanges to pause, and editing,
+//! releasing or dropping the ones that are waiting.
+use leptos::{server_fn::codec::Json, *};
+use leptos_use::use_interval_fn;
+use shared_types::{
+    filter::RequestMatch,
+    intercept::{BreakRule, Paused, Phase, Verdict},
+    RequestHead, ResponseHead,
+};
+
+use crate::{
+    non_empty,
+    replay::{headers_to_text, text_to_headers},
+    FilterInput,
+};
+
+/// How often the inspector looks for newly paused exchanges
+const POLL_INTERVAL_MS: u64 = 1000;
+
+#[server]
+pub async fn get_break_rules() -> Result<Vec<BreakRule>, ServerFnError> {
+    let state =
+        expect_context::<std::sync::Arc<parking_lot::Mutex<crate::breakpoints::Breakpoints>>>();
+    let rules = state.lock().rules().to_vec();
+    Ok(rules)
+}
+
+#[server(input = Json)]
+pub async fn set_break_rules(rules: Vec<BreakRule>) -> Result<(), ServerFnError> {
+    let state =
+        expect_context::<std::sync::Arc<parking_lot::Mutex<crate::breakpoints::Breakpoints>>>();
+    state.lock().set_rules(rules);
+    Ok(())
+}
+
+#[server]
+pub async fn get_paused() -> Result<Vec<Paused>, ServerFnError> {
+    let state =
+        expect_context::<std::sync::Arc<parking_lot::Mutex<crate::breakpoints::Breakpoints>>>();
+    let paused = state.lock().paused();
+    Ok(paused)
+}
+
+/// Let a paused exchange go on or drop it, returns whether it was still waiting.
+/// Edited heads are checked here, so the proxy only gets heads it can send.
+#[server(input = Json)]
+pub async fn resolve_paused(id: u64, verdict: Verdict) -> Result<bool, ServerFnError> {
+    let check_headers = |headers: &[(String, String)]| -> Result<(), ServerFnError> {
+        for (name, value) in headers {
+            http::HeaderName::from_bytes(name.as_bytes())?;
+            http::HeaderValue::from_str(value)?;
+        }
+        Ok(())
+    };
+    match &verdict {
+        Verdict::ReleaseRequest { head, .. } => {
+            http::Method::from_bytes(head.method.as_bytes())?;
+            head.uri.parse::<http::Uri>()?;
+            check_headers(&head.headers)?;
+        }
+        Verdict::ReleaseResponse { head, .. } => {
+            http::StatusCode::from_u16(head.status)?;
+            check_headers(&head.headers)?;
+        }
+        Verdict::Drop => {}
+    }
+    let state =
+        expect_context::<std::sync::Arc<parking_lot::Mutex<crate::breakpoints::Breakpoints>>>();
+    let resolved = state.lock().resolve(id, verdict);
+    Ok(resolved)
+}
+
+fn describe(rule: &BreakRule) -> String {
+    let request = &rule.request;
+    let mut criteria = vec![];
+    if let Some(method) = &request.method {
+        criteria.push(method.to_uppercase());
+    }
+    if let Some(path) = &request.path {
+        criteria.push(path.clone());
+    }
+    match (&request.header_name, &request.header_value) {
+        (Some(name), Some(value)) => criteria.push(format!("{name}: *{value}*")),
+        (Some(name), None) => criteria.push(format!("{name}: *")),
+        (None, Some(value)) => criteria.push(format!("any header: *{value}*")),
+        (None, None) => {}
+    }
+    if criteria.is_empty() {
+        criteria.push("every request".into());
+    }
+    let phases = match (rule.requests, rule.responses) {
+        (true, true) => "requests and responses",
+        (true, false) => "requests",
+        (false, true) => "responses",
+        (false, false) => "nothing",
+    };
+    format!("{}, pausing {phases}", criteria.join(" "))
+}
+
+/// The breakpoint rules, with a form to add one
+#[component]
+fn BreakRules() -> impl IntoView {
+    let save = create_action(|rules: &Vec<BreakRule>| set_break_rules(rules.clone()));
+    let rules = create_resource(move || save.version().get(), |_| get_break_rules());
+    let current = move || rules.get().and_then(Result::ok).unwrap_or_default();
+
+    let method = create_rw_signal(String::new());
+    let path = create_rw_signal(String::new());
+    let header_name = create_rw_signal(String::new());
+    let header_value = create_rw_signal(String::new());
+    let requests = create_rw_signal(true);
+    let responses = create_rw_signal(false);
+    let add = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        let mut updated = current();
+        updated.push(BreakRule {
+            request: RequestMatch {
+                method: non_empty(method.get_untracked()),
+                path: non_empty(path.get_untracked()),
+                header_name: non_empty(header_name.get_untracked()),
+                header_value: non_empty(header_value.get_untracked()),
+            },
+            requests: requests.get_untracked(),
+            responses: responses.get_untracked(),
+        });
+        save.dispatch(updated);
+    };
+
+    view! {
+        <form class="flex flex-wrap items-end gap-2" on:submit=add>
+            <FilterInput label="Method" value=method placeholder="POST"/>
+            <FilterInput label="Path" value=path placeholder="/hooks/*"/>
+            <FilterInput label="Header name" value=header_name/>
+            <FilterInput label="Header value" value=header_value/>
+            <label class="text-sm">
+                <input
+                    type="checkbox"
+                    prop:checked=requests
+                    on:change=move |ev| requests.set(event_target_checked(&ev))
+                />
+                " Requests"
+            </label>
+            <label class="text-sm">
+                <input
+                    type="checkbox"
+                    prop:checked=responses
+                    on:change=move |ev| responses.set(event_target_checked(&ev))
+                />
+                " Responses"
+            </label>
+            <button class="font-semibold border rounded px-2" type="submit">
+                "Add breakpoint"
+            </button>
+        </form>
+        <Transition fallback=move || ()>
+            <ul>
+                {move || {
+                    let rules = current();
+                    rules
+                        .iter()
+                        .enumerate()
+                        .map(|(index, rule)| {
+                            let remaining = {
+                                let mut rules = rules.clone();
+                                rules.remove(index);
+                                rules
+                            };
+                            view! {
+                                <li class="font-mono text-sm">
+                                    {describe(rule)} " "
+                                    <button
+                                        class="font-sans text-red-500"
+                                        on:click=move |_| save.dispatch(remaining.clone())
+                                    >
+                                        "remove"
+                                    </button>
+                                </li>
+                            }
+                        })
+                        .collect_view()
+                }}
+            </ul>
+        </Transition>
+    }
+}
+
+/// One exchange held at a breakpoint, its head and body can be edited before
+/// releasing it
+#[component]
+fn PausedExchange(paused: Paused, resolved: Callback<()>) -> impl IntoView {
+    let Paused {
+        id,
+        phase,
+        request_head,
+        response_head,
+        ports,
+        request_body,
+    } = paused;
+    let response_head = response_head.unwrap_or(ResponseHead {
+        status: 0,
+        headers: vec![],
+    });
+    let method = create_rw_signal(request_head.method.clone());
+    let uri = create_rw_signal(request_head.uri.clone());
+    let status = create_rw_signal(response_head.status.to_string());
+    let headers = create_rw_signal(headers_to_text(match phase {
+        Phase::Request => &request_head.headers,
+        Phase::Response => &response_head.headers,
+    }));
+    // Responses are paused before their body arrives, it can only be replaced
+    let body_held = request_body.is_some();
+    let original_body = request_body.unwrap_or_default();
+    let original_text = String::from_utf8_lossy(&original_body).into_owned();
+    let body = create_rw_signal(original_text.clone());
+    let replace_body = create_rw_signal(false);
+
+    let resolve = create_action(move |release: &bool| {
+        // Left as it was, a body that is not valid utf-8 goes on byte for byte
+        let edited_body = match body.get_untracked() {
+            text if text == original_text => original_body.clone(),
+            text => text.into_bytes(),
+        };
+        let verdict = match (release, phase) {
+            (false, _) => Ok(Verdict::Drop),
+            (true, Phase::Request) => Ok(Verdict::ReleaseRequest {
+                head: RequestHead {
+                    method: method.get_untracked(),
+                    uri: uri.get_untracked(),
+                    headers: text_to_headers(&headers.get_untracked()),
+                },
+                body: body_held.then_some(edited_body),
+            }),
+            (true, Phase::Response) => match status.get_untracked().trim().parse() {
+                Ok(status) => Ok(Verdict::ReleaseResponse {
+                    head: ResponseHead {
+                        status,
+                        headers: text_to_headers(&headers.get_untracked()),
+                    },
+                    body: replace_body.get_untracked().then_some(edited_body),
+                }),
+                Err(_) => Err(ServerFnError::new("the status has to be a number")),
+            },
+        };
+        async move {
+            let waiting = resolve_paused(id, verdict?).await?;
+            waiting
+                .then(|| Callable::call(&resolved, ()))
+                .ok_or_else(|| {
+                    ServerFnError::new("the exchange is not waiting anymore, it may have timed out")
+                })
+        }
+    });
+    let result = resolve.value();
+
+    let summary = format!(
+        "Paused {} on port {}: {} {}",
+        match phase {
+            Phase::Request => "request",
+            Phase::Response => "response",
+        },
+        ports.proxy_port,
+        request_head.method,
+        request_head.uri,
+    );
+    view! {
+        <div class="border border-orange-400 p-4 rounded-lg shadow space-y-2">
+            <p class="font-bold">{summary}</p>
+            {match phase {
+                Phase::Request => {
+                    view! {
+                        <div class="flex space-x-2">
+                            <input
+                                class="border px-1 w-24 font-mono"
+                                prop:value=method
+                                on:input=move |ev| method.set(event_target_value(&ev))
+                            />
+                            <input
+                                class="border px-1 flex-grow font-mono"
+                                prop:value=uri
+                                on:input=move |ev| uri.set(event_target_value(&ev))
+                            />
+                        </div>
+                    }
+                }
+                Phase::Response => {
+                    view! {
+                        <div class="flex space-x-2">
+                            <input
+                                class="border px-1 w-24 font-mono"
+                                prop:value=status
+                                on:input=move |ev| status.set(event_target_value(&ev))
+                            />
+                        </div>
+                    }
+                }
+            }}
+            <textarea
+                class="border px-1 w-full font-mono text-sm"
+                rows=6
+                prop:value=headers
+                on:input=move |ev| headers.set(event_target_value(&ev))
+            ></textarea>
+            {match (phase, body_held) {
+                (Phase::Request, true) => {
+                    view! {
+                        <textarea
+                            class="border px-1 w-full font-mono text-sm"
+                            rows=6
+                            prop:value=body
+                            on:input=move |ev| body.set(event_target_value(&ev))
+                        ></textarea>
+                    }
+                        .into_view()
+                }
+                (Phase::Request, false) => {
+                    view! {
+                        <p class="text-sm text-gray-500">
+                            "The body is chunked or too big to hold, it goes on unchanged"
+                        </p>
+                    }
+                        .into_view()
+                }
+                (Phase::Response, _) => {
+                    view! {
+                        <label class="block text-sm">
+                            <input
+                                type="checkbox"
+                                prop:checked=replace_body
+                                on:change=move |ev| replace_body.set(event_target_checked(&ev))
+                            />
+                            " Replace the body from the local service"
+                        </label>
+                        <Show when=move || replace_body.get()>
+                            <textarea
+                                class="border px-1 w-full font-mono text-sm"
+                                rows=6
+                                prop:value=body
+                                on:input=move |ev| body.set(event_target_value(&ev))
+                            ></textarea>
+                        </Show>
+                    }
+                        .into_view()
+                }
+            }}
+            <div class="flex space-x-2">
+                <button
+                    class="font-semibold border rounded px-2"
+                    disabled=resolve.pending()
+                    on:click=move |_| resolve.dispatch(true)
+                >
+                    "Release"
+                </button>
+                <button
+                    class="font-semibold border rounded px-2 text-red-500"
+                    disabled=resolve.pending()
+                    on:click=move |_| resolve.dispatch(false)
+                >
+                    "Drop"
+                </button>
+            </div>
+            {move || match result.get() {
+                Some(Err(e)) => view! { <p class="text-red-500">{e.to_string()}</p> }.into_view(),
+                _ => ().into_view(),
+            }}
+        </div>
+    }
+}
+
+/// Breakpoint rules and the exchanges currently waiting at them
+#[component]
+pub fn InterceptPanel() -> impl IntoView {
+    let tick = create_rw_signal(0u64);
+    use_interval_fn(move || tick.update(|tick| *tick += 1), POLL_INTERVAL_MS);
+    let paused = create_resource(move || tick.get(), |_| get_paused());
+    // Kept in a signal of its own so polling does not throw away edits in progress
+    let waiting = create_rw_signal(Vec::<Paused>::new());
+    create_effect(move |_| {
+        if let Some(Ok(paused)) = paused.get() {
+            waiting.set(paused);
+        }
+    });
+    let resolved = Callback::new(move |_| tick.update(|tick| *tick += 1));
+
+    view! {
+        <details class="p-4 space-y-2">
+            <summary class="font-semibold">"Breakpoints"</summary>
+            <BreakRules/>
+            <For
+                each=move || waiting.get()
+                key=|paused| paused.id
+                children=move |paused| view! { <PausedExchange paused resolved/> }
+            />
+        </details>
+    }
+}
@@ -1,15 +1,39 @@
-use std::{cmp::min, ops::Deref};
+use crate::{
+    body::BodyView,
+    diff::DiffView,
+    error_template::{AppError, ErrorTemplate},
+    intercept::InterceptPanel,
+    replay::ReplayForm,
+};
 
-use crate::error_template::{AppError, ErrorTemplate};
-
-use leptos::{logging, *};
+use leptos::{logging, server_fn::codec::Json, *};
 use leptos_meta::*;
 use leptos_router::*;
-use shared_types::{RequestCycle, TrafficLog};
+use leptos_use::{use_event_source, utils::JsonCodec, UseEventSourceReturn};
+use shared_types::{
+    filter::{StatusRange, TrafficFilter},
+    snippet::SnippetFormat,
+    BodySizes, RequestCycle, Timings, TrafficPage,
+};
 
+pub mod body;
+#[cfg(feature = "ssr")]
+pub mod breakpoints;
+pub mod diff;
 pub mod error_template;
+pub mod intercept;
+pub mod replay;
+#[cfg(feature = "ssr")]
+pub mod store;
 
-const MAX_BODY_LEN: usize = 100000;
+/// How many requests the UI loads at a time
+const PAGE_SIZE: usize = 50;
+/// Server sent events endpoint on the ui server streaming new request cycles as json
+pub const LIVE_TRAFFIC_PATH: &str = "/api/traffic/live";
+/// Endpoint on the ui server serving single request cycles as json by id, `latest` is the most recent one
+pub const TRAFFIC_PATH: &str = "/api/traffic";
+/// Endpoint on the ui server to download captured traffic as HAR from, or upload a HAR file to
+pub const HAR_PATH: &str = "/api/har";
 
 #[component]
 pub fn App() -> impl IntoView {
@@ -26,6 +50,7 @@ pub fn App() -> impl IntoView {
             <main>
                 <Routes>
                     <Route path="" view=HomePage/>
+                    <Route path="/requests/:id" view=RequestPage/>
                 </Routes>
             </main>
         </Router>
@@ -38,25 +63,312 @@ fn HomePage() -> impl IntoView {
         <section class="text-gray-700 body-font">
             <div class="container mx-auto px-4">
                 <h1 class="text-2xl p-4">"Sgrok Request Overview"</h1>
+                <a class="font-semibold px-4" href=HAR_PATH download="sgrok.har">
+                    "Download as HAR"
+                </a>
+                <InterceptPanel/>
                 <TrafficLogSuspense/>
             </div>
         </section>
     }
 }
 
+/// A single captured request, `None` when there is no request with that id (anymore)
+#[server]
+pub async fn get_request_cycle(id: u64) -> Result<Option<RequestCycle>, ServerFnError> {
+    let state = expect_context::<std::sync::Arc<parking_lot::RwLock<store::TrafficStore>>>();
+    let cycle = state.read().get(id).cloned();
+    Ok(cycle)
+}
+
+/// Forget a captured request, returns whether there was one with that id
 #[server]
-pub async fn get_traffic_log() -> Result<TrafficLog, ServerFnError> {
-    let state = expect_context::<std::sync::Arc<parking_lot::RwLock<TrafficLog>>>();
-    let traffic_log = { state.deref().read().clone() };
-    Ok(traffic_log)
+pub async fn delete_request_cycle(id: u64) -> Result<bool, ServerFnError> {
+    let state = expect_context::<std::sync::Arc<parking_lot::RwLock<store::TrafficStore>>>();
+    let deleted = state.write().remove(id);
+    Ok(deleted)
+}
+
+/// A single captured request on a page of its own, so it can be linked to
+#[component]
+fn RequestPage() -> impl IntoView {
+    let params = use_params_map();
+    let id = move || params.with(|params| params.get("id").and_then(|id| id.parse::<u64>().ok()));
+    let cycle = create_resource(id, |id| async move {
+        match id {
+            Some(id) => get_request_cycle(id).await,
+            None => Ok(None),
+        }
+    });
+    let navigate = use_navigate();
+    let delete = create_action(move |id: &u64| {
+        let (id, navigate) = (*id, navigate.clone());
+        async move {
+            if let Ok(true) = delete_request_cycle(id).await {
+                navigate("/", Default::default());
+            }
+        }
+    });
+    view! {
+        <section class="text-gray-700 body-font">
+            <div class="container mx-auto px-4 space-y-4">
+                <h1 class="text-2xl p-4">"Sgrok Request"</h1>
+                <A class="font-semibold px-4" href="/">
+                    "Back to all requests"
+                </A>
+                <Suspense fallback=move || view! { <p>"Loading request"</p> }>
+                    {move || match cycle.get() {
+                        None => ().into_view(),
+                        Some(Err(e)) => view! { <p>{format!("Could not load request: {e}")}</p> }.into_view(),
+                        Some(Ok(None)) => view! { <p>"There is no such request, it may have been evicted or deleted"</p> }.into_view(),
+                        Some(Ok(Some(cycle))) => {
+                            let id = cycle.id;
+                            let took = cycle.timestamp_out - cycle.timestamp_in;
+                            view! {
+                                <ul class="px-4">
+                                    <li>{format!("Received at {}", cycle.timestamp_in)}</li>
+                                    <li>{format!("Answered after {} ms", took.num_milliseconds())}</li>
+                                    <li>
+                                        {format!(
+                                            "Request body of {} bytes, response body of {} bytes",
+                                            cycle.sizes.request,
+                                            cycle.sizes.response,
+                                        )}
+                                    </li>
+                                    <li>{format!("Status {}, {}", cycle.response_head.status, cycle.outcome)}</li>
+                                    <li>
+                                        {format!(
+                                            "Through the eaves proxy on port {} to port {}",
+                                            cycle.ports.proxy_port,
+                                            cycle.ports.target_port,
+                                        )}
+                                    </li>
+                                </ul>
+                                <Waterfall timings=cycle.timings sizes=cycle.sizes/>
+                                <RequestsCycleView cycle/>
+                                <button
+                                    class="font-semibold border rounded px-2 text-red-500"
+                                    disabled=delete.pending()
+                                    on:click=move |_| delete.dispatch(id)
+                                >
+                                    "Delete this request"
+                                </button>
+                            }
+                                .into_view()
+                        }
+                    }}
+                </Suspense>
+            </div>
+        </section>
+    }
+}
+
+/// Bars showing where the time of an exchange went: connecting to the local
+/// service, waiting on it to respond and passing the response on
+#[component]
+fn Waterfall(timings: Timings, sizes: BodySizes) -> impl IntoView {
+    let phases = timings.phases();
+    let total = phases.iter().map(|(_, _, end)| *end).max().unwrap_or(0);
+    if total == 0 {
+        return view! { <p class="px-4">"No timings were measured for this request"</p> }.into_view();
+    }
+    let percent = |micros: u64| micros as f64 * 100.0 / total as f64;
+    view! {
+        <div class="px-4 space-y-1">
+            {phases
+                .into_iter()
+                .map(|(name, start, end)| {
+                    let color = match name {
+                        "connect" => "bg-yellow-400",
+                        "wait" => "bg-blue-400",
+                        _ => "bg-green-400",
+                    };
+                    let label = match name {
+                        "receive" => format!("{name} {:.1} ms, {} bytes", (end - start) as f64 / 1000.0, sizes.response),
+                        _ => format!("{name} {:.1} ms", (end - start) as f64 / 1000.0),
+                    };
+                    let style = format!(
+                        "margin-left: {:.2}%; width: {:.2}%",
+                        percent(start),
+                        percent(end - start).max(0.5),
+                    );
+                    view! {
+                        <div class="flex items-center text-sm">
+                            <span class="w-64 shrink-0">{label}</span>
+                            <div class="flex-grow">
+                                <div class=format!("h-3 rounded {color}") style=style></div>
+                            </div>
+                        </div>
+                    }
+                })
+                .collect_view()}
+        </div>
+    }
+    .into_view()
+}
+
+/// Up to `limit` captured requests matching the filter, skipping the `offset`
+/// most recent matches. Filtering happens here so large logs stay on the client.
+#[server(input = Json)]
+pub async fn get_traffic_log(
+    filter: TrafficFilter,
+    offset: usize,
+    limit: usize,
+) -> Result<TrafficPage, ServerFnError> {
+    let state = expect_context::<std::sync::Arc<parking_lot::RwLock<store::TrafficStore>>>();
+    let page = state.read().page(&filter, offset, limit);
+    Ok(page)
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_owned())
+}
+
+/// Parse the value of a datetime-local input, the UI shows all times in UTC
+fn utc_time(value: String) -> Option<chrono::DateTime<chrono::Utc>> {
+    let value = non_empty(value)?;
+    ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(&value, format).ok())
+        .map(|time| time.and_utc())
+}
+
+#[component]
+fn FilterInput(
+    label: &'static str,
+    value: RwSignal<String>,
+    #[prop(optional)] placeholder: &'static str,
+    #[prop(default = "text")] input_type: &'static str,
+) -> impl IntoView {
+    view! {
+        <label class="flex flex-col text-sm">
+            {label}
+            <input
+                class="border px-1 font-mono"
+                type=input_type
+                placeholder=placeholder
+                prop:value=value
+                on:input=move |ev| value.set(event_target_value(&ev))
+            />
+        </label>
+    }
+}
+
+/// Inputs for the criteria of a [`TrafficFilter`], applied when the form is submitted
+#[component]
+fn FilterBar(filter: RwSignal<TrafficFilter>) -> impl IntoView {
+    let text = create_rw_signal(String::new());
+    let method = create_rw_signal(String::new());
+    let status = create_rw_signal(String::new());
+    let path = create_rw_signal(String::new());
+    let header_name = create_rw_signal(String::new());
+    let header_value = create_rw_signal(String::new());
+    let body = create_rw_signal(String::new());
+    let since = create_rw_signal(String::new());
+    let until = create_rw_signal(String::new());
+    let status_error = create_rw_signal(None::<String>);
+
+    let apply = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        let status = match non_empty(status.get_untracked()).map(|s| s.parse::<StatusRange>()) {
+            None => None,
+            Some(Ok(range)) => Some(range),
+            Some(Err(e)) => {
+                status_error.set(Some(e.to_string()));
+                return;
+            }
+        };
+        status_error.set(None);
+        filter.set(TrafficFilter {
+            text: non_empty(text.get_untracked()),
+            method: non_empty(method.get_untracked()),
+            status,
+            path: non_empty(path.get_untracked()),
+            header_name: non_empty(header_name.get_untracked()),
+            header_value: non_empty(header_value.get_untracked()),
+            body: non_empty(body.get_untracked()),
+            since: utc_time(since.get_untracked()),
+            until: utc_time(until.get_untracked()),
+        });
+    };
+    let clear = move |_| {
+        for value in [text, method, status, path, header_name, header_value, body, since, until] {
+            value.set(String::new());
+        }
+        status_error.set(None);
+        filter.set(TrafficFilter::default());
+    };
+
+    view! {
+        <form class="border p-4 rounded-lg space-y-2" on:submit=apply>
+            <div class="grid grid-cols-3 gap-2">
+                <FilterInput label="Search" value=text placeholder="anywhere in the request"/>
+                <FilterInput label="Method" value=method placeholder="POST"/>
+                <FilterInput label="Status" value=status placeholder="404, 5xx or 200-299"/>
+                <FilterInput label="Path" value=path placeholder="/hooks/*"/>
+                <FilterInput label="Header name" value=header_name placeholder="x-github-event"/>
+                <FilterInput label="Header value" value=header_value/>
+                <FilterInput label="Body contains" value=body/>
+                <FilterInput label="Since (UTC)" value=since input_type="datetime-local"/>
+                <FilterInput label="Until (UTC)" value=until input_type="datetime-local"/>
+            </div>
+            {move || status_error.get().map(|e| view! { <p class="text-red-500">{e}</p> })}
+            <button class="font-semibold border rounded px-2" type="submit">
+                "Filter"
+            </button>
+            <button
+                class="font-semibold border rounded px-2"
+                type="button"
+                class:hidden=move || filter.with(TrafficFilter::is_empty)
+                on:click=clear
+            >
+                "Clear"
+            </button>
+        </form>
+    }
+}
+
+/// The request cycles picked in the traffic log to compare, at most two
+#[derive(Copy, Clone)]
+struct CompareSelection(RwSignal<Vec<RequestCycle>>);
+
+#[component]
+fn ComparePanel() -> impl IntoView {
+    let CompareSelection(selection) = expect_context();
+    move || {
+        selection.with(|selected| match selected.as_slice() {
+            [first, second] => {
+                let (left, right) = match first.timestamp_in <= second.timestamp_in {
+                    true => (first.clone(), second.clone()),
+                    false => (second.clone(), first.clone()),
+                };
+                view! {
+                    <DiffView left right/>
+                    <button class="font-semibold" on:click=move |_| selection.set(vec![])>
+                        "Stop comparing"
+                    </button>
+                }
+                    .into_view()
+            }
+            [_] => view! { <p>"Select one more request to compare it with"</p> }.into_view(),
+            _ => ().into_view(),
+        })
+    }
 }
 
 #[component]
 fn TrafficLogSuspense() -> impl IntoView {
-    // new resource with no dependencies (it will only called once)
-    let traffic_log = create_resource(|| (), |_| async { get_traffic_log().await });
-    // logging::log!("displaying trafficlog");
+    provide_context(CompareSelection(create_rw_signal(vec![])));
+    let filter = create_rw_signal(TrafficFilter::default());
+    // Fetched again whenever the filter changes
+    let traffic_log = create_resource(
+        move || filter.get(),
+        |filter| async move { get_traffic_log(filter, 0, PAGE_SIZE).await },
+    );
     view! {
+        <FilterBar filter/>
+        <ComparePanel/>
         <Suspense fallback=move || view! { <p>"Loading Trafficlog"</p> }>
             <ErrorBoundary fallback=|_e| {
                 view! { <p>"Could not load trafficlog"</p> }
@@ -67,7 +379,8 @@ fn TrafficLogSuspense() -> impl IntoView {
                         .map(move |x| {
                             x.map(move |y| {
                                 TrafficLogRequests(TrafficLogRequestsProps {
-                                    reqs: y.requests,
+                                    page: y,
+                                    filter,
                                 })
                             })
                         })
@@ -79,14 +392,51 @@ fn TrafficLogSuspense() -> impl IntoView {
 }
 
 #[component]
-fn TrafficLogRequests(
-    reqs: Vec<RequestCycle>) -> impl IntoView {
-    let (tlog, _set_tlog) = create_signal(reqs);
+fn TrafficLogRequests(page: TrafficPage, filter: RwSignal<TrafficFilter>) -> impl IntoView {
+    let has_older = create_rw_signal(page.requests.len() < page.total);
+    let (tlog, set_tlog) = create_signal(page.requests);
+    let load_older = create_action(move |offset: &usize| {
+        get_traffic_log(filter.get_untracked(), *offset, PAGE_SIZE)
+    });
+    create_effect(move |_| {
+        if let Some(Ok(older)) = load_older.value().get() {
+            has_older.set(older.offset + older.requests.len() < older.total);
+            set_tlog.update(|tlog| {
+                let mut requests = older.requests;
+                requests.retain(|cycle| !tlog.contains(cycle));
+                requests.append(tlog);
+                *tlog = requests;
+            });
+        }
+    });
+    let UseEventSourceReturn { data, .. } =
+        use_event_source::<RequestCycle, JsonCodec>(LIVE_TRAFFIC_PATH);
+    create_effect(move |_| {
+        if let Some(cycle) = data.get() {
+            if !filter.with_untracked(|filter| filter.matches(&cycle)) {
+                return;
+            }
+            // A cycle recorded while the log was loading is both in the log and on the stream
+            set_tlog.update(|tlog| {
+                if !tlog.contains(&cycle) {
+                    tlog.push(cycle)
+                }
+            });
+        }
+    });
     view! {
         <div class="space-y-4">
+            <button
+                class="font-semibold"
+                class:hidden=move || !has_older()
+                disabled=load_older.pending()
+                on:click=move |_| load_older.dispatch(tlog.with(Vec::len))
+            >
+                "Load older requests"
+            </button>
             <For
                 each=move || tlog.get()
-                key=|cycle| cycle.timestamp_in.timestamp()
+                key=|cycle| cycle.id
                 children=move |cycle: shared_types::RequestCycle| {
                     view! { <RequestsCycleView cycle=cycle/> }
                 }
@@ -101,12 +451,21 @@ fn RequestsCycleView(cycle: RequestCycle) -> impl IntoView {
     let collapsed  = create_rw_signal(false);
     let hidden_req_headers = create_rw_signal(true);
     let hidden_resp_headers = create_rw_signal(true);
-    let hidden_body = create_rw_signal(true);
+    let hidden_req_body = create_rw_signal(true);
+    let hidden_resp_body = create_rw_signal(true);
+    let hidden_replay = create_rw_signal(true);
+    let replayable = cycle.clone();
+    let copyable = cycle.clone();
+    let compared = cycle.clone();
+    let link = format!("/requests/{}", cycle.id);
 
-    let body_len = cycle.response_body.len();
-    let response_str = String::from_utf8_lossy(&cycle.response_body[..min(body_len, MAX_BODY_LEN)]).into_owned();
+    let failure = cycle.outcome.is_failure().then(|| cycle.outcome.to_string());
+    let failed = failure.is_some();
+    let applied_rules = (!cycle.rules.is_empty()).then(|| format!("Rules: {}", cycle.rules.join(", ")));
+    let req_body_empty = cycle.request_body.is_empty();
+    let resp_body_empty = cycle.response_body.is_empty();
     view! {
-        <div class="border p-4 rounded-lg shadow">
+        <div class="border p-4 rounded-lg shadow" class:border-red-500=failed class:bg-red-50=failed>
             <button
                 class="w-full text-left"
                 on:click=move |_| {
@@ -115,9 +474,17 @@ fn RequestsCycleView(cycle: RequestCycle) -> impl IntoView {
                 }
             >
 
-                <p class="font-bold">{"Timestamp: "} {cycle.timestamp_in.to_string()}</p>
+                <p class="font-bold">
+                    {format!("#{} ", cycle.id)} {"Timestamp: "} {cycle.timestamp_in.to_string()}
+                </p>
                 <p>{cycle.request_head.uri}</p>
+                {failure.map(|failure| view! { <p class="text-red-500 font-semibold">{failure}</p> })}
+                {applied_rules.map(|rules| view! { <p class="text-purple-600 text-sm">{rules}</p> })}
             </button>
+            <div class="flex space-x-4 text-sm">
+                <A href=link>"Link to this request"</A>
+                <CompareToggle cycle=compared/>
+            </div>
             <div class:hidden=move || collapsed()>
                 <div>
                     <button
@@ -127,7 +494,18 @@ fn RequestsCycleView(cycle: RequestCycle) -> impl IntoView {
                         "Display request headers"
                     </button>
                     <div class:hidden=move || hidden_req_headers()>
-                        <Headers headers=cycle.request_head.headers/>
+                        <Headers headers=cycle.request_head.headers.clone()/>
+                    </div>
+                </div>
+                <div class:hidden=req_body_empty>
+                    <button
+                        class="font-semibold"
+                        on:click=move |_| { hidden_req_body.update(|b| *b = !*b) }
+                    >
+                        "Display request body"
+                    </button>
+                    <div class="pl-4" class:hidden=move || hidden_req_body()>
+                        <BodyView headers=cycle.request_head.headers body=cycle.request_body/>
                     </div>
                 </div>
                 <div>
@@ -138,30 +516,102 @@ fn RequestsCycleView(cycle: RequestCycle) -> impl IntoView {
                         "Display response headers"
                     </button>
                     <div class:hidden=move || hidden_resp_headers()>
-                        <Headers headers=cycle.response_head.headers/>
+                        <Headers headers=cycle.response_head.headers.clone()/>
+                    </div>
+                </div>
+                <div class:hidden=resp_body_empty>
+                    <button
+                        class="font-semibold"
+                        on:click=move |_| { hidden_resp_body.update(|b| *b = !*b) }
+                    >
+                        "Display response body"
+                    </button>
+                    <div class="pl-4" class:hidden=move || hidden_resp_body()>
+                        <BodyView headers=cycle.response_head.headers body=cycle.response_body/>
                     </div>
                 </div>
-                <div class:hidden=move || body_len == 0>
+                <div>
                     <button
                         class="font-semibold"
-                        on:click=move |_| { hidden_body.update(|b| *b = !*b) }
+                        on:click=move |_| { hidden_replay.update(|b| *b = !*b) }
                     >
-                        "Display Request Body"
+                        "Edit and replay"
                     </button>
-                    <div class:hidden=move || hidden_body()>
-                        <p class="pl-4 text-sm">
-                            {response_str}
-                            <span class:hidden=move || body_len < MAX_BODY_LEN class="text-red-500">
-                                (response truncated)
-                            </span>
-                        </p>
+                    <div class:hidden=move || hidden_replay()>
+                        <ReplayForm cycle=replayable/>
                     </div>
                 </div>
+                <SnippetMenu cycle=copyable/>
             </div>
         </div>
     }
 }
 
+/// Pick a request for comparison, only shown in the traffic log
+#[component]
+fn CompareToggle(cycle: RequestCycle) -> impl IntoView {
+    let CompareSelection(selection) = use_context()?;
+    let selected = {
+        let cycle = cycle.clone();
+        move || selection.with(|selected| selected.contains(&cycle))
+    };
+    let toggle = move |ev| {
+        let cycle = cycle.clone();
+        selection.update(|selected| match event_target_checked(&ev) {
+            true => {
+                // Picking a third request replaces the first one picked
+                if selected.len() == 2 {
+                    selected.remove(0);
+                }
+                selected.push(cycle);
+            }
+            false => selected.retain(|picked| *picked != cycle),
+        });
+    };
+    Some(view! {
+        <label>
+            <input type="checkbox" prop:checked=selected on:change=toggle/>
+            " Compare"
+        </label>
+    })
+}
+
+/// Pick a tool to get the request as a snippet for it, ready to copy
+#[component]
+fn SnippetMenu(cycle: RequestCycle) -> impl IntoView {
+    let format = create_rw_signal(None::<SnippetFormat>);
+    let snippet = create_memo(move |_| format.get().map(|format| format.render(&cycle)));
+    let textarea = create_node_ref::<html::Textarea>();
+    view! {
+        <div>
+            <select
+                class="font-semibold"
+                on:change=move |ev| format.set(event_target_value(&ev).parse().ok())
+            >
+                <option value="">"Copy as..."</option>
+                {SnippetFormat::ALL
+                    .into_iter()
+                    .map(|format| view! { <option value=format.name()>{format.name()}</option> })
+                    .collect_view()}
+            </select>
+            <textarea
+                class="border px-1 w-full font-mono text-sm"
+                class:hidden=move || snippet.with(Option::is_none)
+                rows=8
+                readonly
+                node_ref=textarea
+                prop:value=move || snippet.get().unwrap_or_default()
+                // Selected as a whole so a single copy grabs all of it
+                on:focus=move |_| {
+                    if let Some(textarea) = textarea.get() {
+                        textarea.select();
+                    }
+                }
+            ></textarea>
+        </div>
+    }
+}
+
 #[component]
 fn Headers(
     headers: Vec<(String, String)>) -> impl IntoView {
@@ -0,0 +1,203 @@
+use leptos::{server_fn::codec::Json, *};
+use shared_types::{RequestCycle, RequestHead};
+
+use crate::body::BodyView;
+
+/// Send a request to the local service behind a tunnel, wherever it runs. With
+/// `capture` set the request goes through the eaves proxy of the tunnel, so the
+/// replay shows up in the traffic log like any other request.
+///
+/// The tunnel is named by the port of its eaves proxy, where it leads is looked
+/// up among the tunnels this client opened, so replays only reach those.
+#[server(input = Json)]
+pub async fn replay_request(
+    head: RequestHead,
+    body: Vec<u8>,
+    proxy_port: u16,
+    capture: bool,
+) -> Result<RequestCycle, ServerFnError> {
+    use chrono::Utc;
+    use shared_types::{
+        snippet::SKIPPED_HEADERS, BodySizes, Outcome, ResponseHead, Timings, TunnelPorts,
+    };
+
+    let ports = expect_context::<std::sync::Arc<[TunnelPorts]>>()
+        .iter()
+        .find(|ports| ports.proxy_port == proxy_port)
+        .cloned()
+        .ok_or_else(|| {
+            ServerFnError::new(format!("no open tunnel proxies on port {proxy_port}"))
+        })?;
+
+    let uri = head.uri.parse::<http::Uri>()?;
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    // The eaves proxy knows how to reach the local service, otherwise it is dialed here
+    let url = match capture {
+        true => format!("http://127.0.0.1:{}{path}", ports.proxy_port),
+        false => ports.target_url(path),
+    };
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(!capture && ports.insecure_target)
+        .build()?;
+    let method = reqwest::Method::from_bytes(head.method.as_bytes())?;
+    let mut request = client.request(method, url);
+    for (name, value) in &head.headers {
+        if !SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            request = request.header(name, value);
+        }
+    }
+
+    let timestamp_in = Utc::now();
+    let started = std::time::Instant::now();
+    let micros_since_start = || Some(started.elapsed().as_micros() as u64);
+    let response = request.body(body.clone()).send().await?;
+    let first_byte = micros_since_start();
+    let response_head = ResponseHead {
+        status: response.status().as_u16(),
+        headers: response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_owned(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect(),
+    };
+    let response_body = response.bytes().await?.to_vec();
+    let timings = Timings {
+        // The http client connects as part of sending, it does not say how long that took
+        connected: None,
+        first_byte,
+        done: micros_since_start(),
+    };
+    let sizes = BodySizes {
+        request: body.len() as u64,
+        response: response_body.len() as u64,
+    };
+    Ok(RequestCycle {
+        // The replay itself is not stored, only a captured one gets an id from the eaves proxy
+        id: 0,
+        timestamp_in,
+        request_head: head,
+        request_body: body,
+        timestamp_out: Utc::now(),
+        response_head,
+        response_body,
+        ports,
+        outcome: Outcome::Completed,
+        timings,
+        sizes,
+        rules: vec![],
+    })
+}
+
+pub(crate) fn headers_to_text(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub(crate) fn text_to_headers(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// Edit a captured request and send it again
+#[component]
+pub fn ReplayForm(cycle: RequestCycle) -> impl IntoView {
+    let method = create_rw_signal(cycle.request_head.method);
+    let uri = create_rw_signal(cycle.request_head.uri);
+    let headers = create_rw_signal(headers_to_text(&cycle.request_head.headers));
+    let original_body = cycle.request_body;
+    let original_text = String::from_utf8_lossy(&original_body).into_owned();
+    let body = create_rw_signal(original_text.clone());
+    let capture = create_rw_signal(true);
+    let proxy_port = cycle.ports.proxy_port;
+
+    let replay = create_action(move |_: &()| {
+        let head = RequestHead {
+            method: method.get_untracked(),
+            uri: uri.get_untracked(),
+            headers: text_to_headers(&headers.get_untracked()),
+        };
+        let body = match body.get_untracked() {
+            // Binary bodies only survive as long as nobody edits them as text
+            text if text == original_text => original_body.clone(),
+            text => text.into_bytes(),
+        };
+        let capture = capture.get_untracked();
+        async move { replay_request(head, body, proxy_port, capture).await }
+    });
+    let result = replay.value();
+
+    view! {
+        <div class="pl-4 space-y-2">
+            <div class="flex space-x-2">
+                <input
+                    class="border px-1 w-24 font-mono"
+                    prop:value=method
+                    on:input=move |ev| method.set(event_target_value(&ev))
+                />
+                <input
+                    class="border px-1 flex-grow font-mono"
+                    prop:value=uri
+                    on:input=move |ev| uri.set(event_target_value(&ev))
+                />
+            </div>
+            <textarea
+                class="border px-1 w-full font-mono text-sm"
+                rows=6
+                prop:value=headers
+                on:input=move |ev| headers.set(event_target_value(&ev))
+            ></textarea>
+            <textarea
+                class="border px-1 w-full font-mono text-sm"
+                rows=6
+                prop:value=body
+                on:input=move |ev| body.set(event_target_value(&ev))
+            ></textarea>
+            <label class="block">
+                <input
+                    type="checkbox"
+                    prop:checked=capture
+                    on:change=move |ev| capture.set(event_target_checked(&ev))
+                />
+                " Capture the replay in the traffic log"
+            </label>
+            <button
+                class="font-semibold border rounded px-2"
+                disabled=replay.pending()
+                on:click=move |_| replay.dispatch(())
+            >
+                "Send"
+            </button>
+            {move || match result.get() {
+                None => ().into_view(),
+                Some(Ok(replayed)) => {
+                    view! {
+                        <p class="font-semibold">
+                            {format!("Replayed, got status {}", replayed.response_head.status)}
+                        </p>
+                        <BodyView
+                            headers=replayed.response_head.headers
+                            body=replayed.response_body
+                        />
+                    }
+                        .into_view()
+                }
+                Some(Err(e)) => {
+                    view! { <p class="text-red-500">{format!("Replay failed: {e}")}</p> }
+                        .into_view()
+                }
+            }}
+
+        </div>
+    }
+}
@@ -0,0 +1,544 @@
+//! Storage for the traffic the eaves proxies capture.
+//!
+//! Request cycles are kept in memory up to a configurable number of requests and
+//! bytes. When a file is configured every cycle is also appended to it as a line
+//! of json, so the captured traffic survives a restart of the client. Evicted
+//! cycles are only dropped from the file once it is compacted, which happens
+//! when it holds more stale lines than live cycles. Deleted cycles get a
+//! tombstone line until then, so they do not come back after a restart.
+//!
+//! The file is written on a thread of its own, the proxies never wait on the
+//! disk while they hold the store.
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+use leptos::logging;
+use serde::{Deserialize, Serialize};
+use shared_types::{filter::TrafficFilter, RequestCycle, TrafficPage};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// Make room for new requests by dropping the oldest ones
+    #[default]
+    DropOldest,
+    /// Stop capturing once the store is full
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub max_requests: usize,
+    /// Upper limit on the combined size of the stored heads and bodies
+    pub max_bytes: usize,
+    pub eviction: Eviction,
+    /// Request bodies are only captured up to this size, they are always forwarded in full
+    pub max_body_bytes: usize,
+    /// Append only json lines file to persist captured traffic in
+    pub file: Option<PathBuf>,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            max_requests: 1000,
+            max_bytes: 64 * 1024 * 1024,
+            eviction: Eviction::default(),
+            max_body_bytes: 10 * 1024 * 1024,
+            file: None,
+        }
+    }
+}
+
+/// Line that marks the cycle with this id as deleted
+#[derive(Debug, Serialize, Deserialize)]
+struct Tombstone {
+    deleted: u64,
+}
+
+/// Just enough of a line to tell which cycle it holds
+#[derive(Debug, Deserialize)]
+struct CycleId {
+    id: u64,
+}
+
+/// Changes to the file, the writer thread applies them in order
+#[derive(Debug)]
+enum FileOp {
+    Append(Box<RequestCycle>),
+    Delete(u64),
+    /// Drop every line but those of the cycles with these ids
+    Compact(HashSet<u64>),
+}
+
+#[derive(Debug)]
+struct LogFile {
+    ops: mpsc::Sender<FileOp>,
+    writer: Option<thread::JoinHandle<()>>,
+    /// Lines in the file that belong to evicted or deleted cycles, tombstones included
+    stale_lines: usize,
+}
+
+impl LogFile {
+    fn start(path: PathBuf, stale_lines: usize) -> io::Result<Self> {
+        let file = open_for_append(&path)?;
+        let (ops, received) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("traffic-file".into())
+            .spawn(move || write_ops(path, file, received))?;
+        Ok(LogFile {
+            ops,
+            writer: Some(writer),
+            stale_lines,
+        })
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer finish what is queued and stop
+        drop(mem::replace(&mut self.ops, mpsc::channel().0));
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TrafficStore {
+    config: StoreConfig,
+    cycles: VecDeque<RequestCycle>,
+    bytes: usize,
+    file: Option<LogFile>,
+    /// Id the next cycle gets, ids keep increasing across restarts when a file is used
+    next_id: u64,
+}
+
+/// Rough size of a cycle, the bodies dominate it for anything interesting
+fn cycle_size(cycle: &RequestCycle) -> usize {
+    let headers = |headers: &[(String, String)]| -> usize {
+        headers.iter().map(|(k, v)| k.len() + v.len()).sum()
+    };
+    cycle.request_head.uri.len()
+        + headers(&cycle.request_head.headers)
+        + headers(&cycle.response_head.headers)
+        + cycle.request_body.len()
+        + cycle.response_body.len()
+}
+
+fn open_for_append(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+fn write_line<T: Serialize>(writer: &mut BufWriter<File>, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, value)?;
+    writer.write_all(b"\n")
+}
+
+/// Apply changes to the file until the store goes away. On the first error the
+/// writer stops, the store notices and keeps its traffic in memory only.
+fn write_ops(path: PathBuf, mut file: BufWriter<File>, ops: mpsc::Receiver<FileOp>) {
+    for op in ops {
+        let written = match op {
+            FileOp::Append(cycle) => write_line(&mut file, &cycle),
+            FileOp::Delete(id) => write_line(&mut file, &Tombstone { deleted: id }),
+            FileOp::Compact(live) => file
+                .flush()
+                .and_then(|_| compact(&path, &live))
+                .and_then(|_| open_for_append(&path))
+                .map(|reopened| file = reopened),
+        }
+        .and_then(|_| file.flush());
+        if let Err(e) = written {
+            logging::warn!("could not persist traffic to {path:?}, only keeping it in memory: {e}");
+            return;
+        }
+    }
+}
+
+/// Rewrite the file with only the lines of the cycles that are still stored
+fn compact(path: &Path, live: &HashSet<u64>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        // Tombstones have no id, they go along with the cycles they buried
+        let keep =
+            serde_json::from_str::<CycleId>(&line).is_ok_and(|cycle| live.contains(&cycle.id));
+        if keep {
+            tmp.write_all(line.as_bytes())?;
+            tmp.write_all(b"\n")?;
+        }
+    }
+    tmp.flush()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Write the file anew from what is in memory
+fn rewrite<'a>(path: &Path, cycles: impl Iterator<Item = &'a RequestCycle>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+    for cycle in cycles {
+        write_line(&mut tmp, cycle)?;
+    }
+    tmp.flush()?;
+    fs::rename(&tmp_path, path)
+}
+
+impl TrafficStore {
+    pub fn new(config: StoreConfig) -> Self {
+        TrafficStore {
+            config,
+            cycles: VecDeque::new(),
+            bytes: 0,
+            file: None,
+            next_id: 1,
+        }
+    }
+
+    /// Open a store, loading whatever the configured file still holds from a
+    /// previous run.
+    pub fn open(config: StoreConfig) -> io::Result<Self> {
+        let path = config.file.clone();
+        let mut store = TrafficStore::new(config);
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(store),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lines = 0;
+        let mut without_ids = false;
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                lines += 1;
+                if let Ok(Tombstone { deleted }) = serde_json::from_str(&line) {
+                    store.take(deleted);
+                    continue;
+                }
+                match serde_json::from_str::<RequestCycle>(&line) {
+                    Ok(mut cycle) => {
+                        // Files written before cycles had ids
+                        if cycle.id == 0 {
+                            cycle.id = store.next_id;
+                            without_ids = true;
+                        }
+                        store.next_id = store.next_id.max(cycle.id + 1);
+                        store.insert(cycle);
+                    }
+                    Err(e) => logging::warn!("skipping unreadable line {lines} in {path:?}: {e}"),
+                }
+            }
+        }
+        // Compaction goes by id, so the ids handed out here have to be in the file first
+        if without_ids {
+            rewrite(&path, store.cycles.iter())?;
+            lines = store.cycles.len();
+        }
+        store.file = Some(LogFile::start(path, lines - store.cycles.len())?);
+        store.compact_if_stale();
+        Ok(store)
+    }
+
+    /// Reserve an id for a new cycle
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Store a cycle, returns whether it was kept
+    pub fn push(&mut self, cycle: RequestCycle) -> bool {
+        let evicted_before = self.cycles.len();
+        if !self.insert(cycle) {
+            return false;
+        }
+        if let Some(file) = &mut self.file {
+            file.stale_lines += evicted_before + 1 - self.cycles.len();
+            let newest = self
+                .cycles
+                .back()
+                .expect("a cycle was just inserted")
+                .clone();
+            self.persist(FileOp::Append(Box::new(newest)));
+        }
+        self.compact_if_stale();
+        true
+    }
+
+    /// Hand a change to the writer thread
+    fn persist(&mut self, op: FileOp) {
+        let sent = self.file.as_ref().map(|file| file.ops.send(op).is_ok());
+        // The writer only stops after saying why
+        if sent == Some(false) {
+            self.file = None;
+        }
+    }
+
+    /// Insert into memory, evicting according to the policy
+    fn insert(&mut self, cycle: RequestCycle) -> bool {
+        let size = cycle_size(&cycle);
+        if size > self.config.max_bytes || self.config.max_requests == 0 {
+            return false;
+        }
+        let full = |store: &Self| {
+            store.cycles.len() >= store.config.max_requests
+                || store.bytes + size > store.config.max_bytes
+        };
+        match self.config.eviction {
+            Eviction::DropNewest if full(self) => return false,
+            Eviction::DropNewest => {}
+            Eviction::DropOldest => {
+                while full(self) {
+                    let oldest = self.cycles.pop_front().expect("a full store is not empty");
+                    self.bytes -= cycle_size(&oldest);
+                }
+            }
+        }
+        self.bytes += size;
+        self.cycles.push_back(cycle);
+        true
+    }
+
+    /// Forget a cycle, returns whether it was stored
+    pub fn remove(&mut self, id: u64) -> bool {
+        if self.take(id).is_none() {
+            return false;
+        }
+        // Unlike evicted cycles a deleted one should not come back after a restart
+        if let Some(file) = &mut self.file {
+            // The line of the cycle and its tombstone
+            file.stale_lines += 2;
+        }
+        self.persist(FileOp::Delete(id));
+        self.compact_if_stale();
+        true
+    }
+
+    /// Take a cycle out of memory
+    fn take(&mut self, id: u64) -> Option<RequestCycle> {
+        let index = self.cycles.iter().position(|cycle| cycle.id == id)?;
+        let taken = self.cycles.remove(index)?;
+        self.bytes -= cycle_size(&taken);
+        Some(taken)
+    }
+
+    fn compact_if_stale(&mut self) {
+        let file = match &mut self.file {
+            Some(file) if file.stale_lines > self.cycles.len() => file,
+            _ => return,
+        };
+        file.stale_lines = 0;
+        let live = self.cycles.iter().map(|cycle| cycle.id).collect();
+        self.persist(FileOp::Compact(live));
+    }
+
+    /// Up to `limit` cycles matching the filter, skipping the `offset` newest
+    /// matches. The page itself is ordered oldest first.
+    pub fn page(&self, filter: &TrafficFilter, offset: usize, limit: usize) -> TrafficPage {
+        let matching: Vec<&RequestCycle> = self
+            .cycles
+            .iter()
+            .rev()
+            .filter(|cycle| filter.matches(cycle))
+            .collect();
+        let mut requests: Vec<RequestCycle> = matching
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|cycle| (*cycle).clone())
+            .collect();
+        requests.reverse();
+        TrafficPage {
+            requests,
+            total: matching.len(),
+            offset,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cycles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cycles.is_empty()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&RequestCycle> {
+        // Recent cycles are looked up most
+        self.cycles.iter().rev().find(|cycle| cycle.id == id)
+    }
+
+    /// All stored cycles, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RequestCycle> {
+        self.cycles.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use shared_types::RequestHead;
+
+    fn cycle(millis: i64, body_len: usize) -> RequestCycle {
+        let timestamp = Utc.timestamp_millis_opt(millis).unwrap();
+        RequestCycle {
+            id: millis as u64 + 1,
+            timestamp_in: timestamp,
+            request_head: RequestHead {
+                method: "POST".into(),
+                uri: "/".into(),
+                headers: vec![],
+            },
+            request_body: vec![b'x'; body_len],
+            timestamp_out: timestamp,
+            ..RequestCycle::default()
+        }
+    }
+
+    fn config(eviction: Eviction) -> StoreConfig {
+        StoreConfig {
+            max_requests: 3,
+            max_bytes: 100,
+            eviction,
+            max_body_bytes: 100,
+            file: None,
+        }
+    }
+
+    #[test]
+    fn evicts_by_count_and_bytes() {
+        let mut store = TrafficStore::new(config(Eviction::DropOldest));
+        for millis in 0..4 {
+            assert!(store.push(cycle(millis, 10)));
+        }
+        assert_eq!(
+            store.page(&TrafficFilter::default(), 0, 10).requests,
+            vec![cycle(1, 0), cycle(2, 0), cycle(3, 0)]
+        );
+        assert!(store.push(cycle(4, 80)));
+        assert_eq!(
+            store.page(&TrafficFilter::default(), 0, 10).requests,
+            vec![cycle(3, 0), cycle(4, 0)]
+        );
+        assert!(!store.push(cycle(5, 101)));
+
+        let mut store = TrafficStore::new(config(Eviction::DropNewest));
+        for millis in 0..3 {
+            assert!(store.push(cycle(millis, 10)));
+        }
+        assert!(!store.push(cycle(3, 10)));
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn pages_count_back_from_the_newest() {
+        let mut store = TrafficStore::new(config(Eviction::DropOldest));
+        for millis in 0..3 {
+            store.push(cycle(millis, 0));
+        }
+        let page = store.page(&TrafficFilter::default(), 1, 1);
+        assert_eq!(page.requests, vec![cycle(1, 0)]);
+        assert_eq!(page.total, 3);
+        assert!(store
+            .page(&TrafficFilter::default(), 3, 10)
+            .requests
+            .is_empty());
+
+        store.push(cycle(3, 10));
+        let with_body = TrafficFilter {
+            body: Some("x".into()),
+            ..TrafficFilter::default()
+        };
+        let page = store.page(&with_body, 0, 10);
+        assert_eq!(page.requests, vec![cycle(3, 0)]);
+        assert_eq!(page.total, 1);
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("sgrok-store-test-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = StoreConfig {
+            file: Some(path.clone()),
+            ..config(Eviction::DropOldest)
+        };
+        let mut store = TrafficStore::open(config.clone()).unwrap();
+        for millis in 0..10 {
+            store.push(cycle(millis, 1));
+        }
+        drop(store);
+        let mut store = TrafficStore::open(config.clone()).unwrap();
+        assert_eq!(
+            store.page(&TrafficFilter::default(), 0, 10).requests,
+            vec![cycle(7, 0), cycle(8, 0), cycle(9, 0)]
+        );
+        assert_eq!(
+            store.page(&TrafficFilter::default(), 0, 1).requests[0].request_body,
+            vec![b'x']
+        );
+        assert_eq!(
+            store.get(9).map(|cycle| cycle.timestamp_in),
+            Some(cycle(8, 0).timestamp_in)
+        );
+        assert!(store.get(1).is_none());
+        assert_eq!(store.next_id(), 11);
+        assert!(store.remove(9));
+        assert!(!store.remove(9));
+        drop(store);
+        let store = TrafficStore::open(config).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get(9).is_none());
+        drop(store);
+        assert!(fs::read_to_string(&path).unwrap().lines().count() <= 6);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deleted_cycles_stay_deleted_until_compaction() {
+        let path = std::env::temp_dir().join(format!(
+            "sgrok-store-delete-test-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let config = StoreConfig {
+            file: Some(path.clone()),
+            ..config(Eviction::DropOldest)
+        };
+        let mut store = TrafficStore::open(config.clone()).unwrap();
+        for millis in 0..3 {
+            store.push(cycle(millis, 1));
+        }
+        assert!(store.remove(2));
+        drop(store);
+        let lines = fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 4);
+        assert_eq!(lines.lines().last(), Some(r#"{"deleted":2}"#));
+
+        let mut store = TrafficStore::open(config).unwrap();
+        assert_eq!(
+            store.page(&TrafficFilter::default(), 0, 10).requests,
+            vec![cycle(0, 0), cycle(2, 0)]
+        );
+        assert!(store.remove(1));
+        drop(store);
+        // More stale lines than live cycles, only the last one is left
+        let lines = fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        assert!(lines.starts_with(r#"{"id":3,"#));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,29 +1,112 @@
+use app::{
+    breakpoints::{Breakpoints, MAX_PAUSE},
+    store::TrafficStore,
+};
 use axum::http::{HeaderName, HeaderValue};
 use bytes::Bytes;
 use chrono::Utc;
 use pingora::services::listening::Service;
-use shared_types::{RequestCycle, RequestHead, ResponseHead, TrafficLog};
-use std::sync::Arc;
+use shared_types::{
+    intercept::{fit_to_body, Phase, Verdict},
+    rules::{mock_for, rewrites_for, MockResponse, ProxyRule},
+    BodySizes, Outcome, RequestCycle, RequestHead, ResponseHead, Timings, TunnelPorts,
+};
+use std::{cmp::min, net::IpAddr, os::unix::io::RawFd, sync::Arc, time::Instant};
+use tokio::sync::broadcast;
 
 use async_trait::async_trait;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
+use pingora_core::protocols::Digest;
 use pingora_core::upstreams::peer::HttpPeer;
-use pingora_core::Result;
+use pingora_core::{Error, ErrorSource, ErrorType, Result};
+use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_proxy::{HttpProxy, ProxyHttp, Session};
 
+/// Port of the eaves proxy of the first http tunnel, the next tunnels count up from here
+pub const FIRST_PROXY_PORT: u16 = 6190;
+
+/// Request bodies up to this size are held at breakpoints so they can be edited.
+/// Pingora keeps this much of a body it already read to send it on afterwards.
+const MAX_HELD_BODY: usize = 64 * 1024;
+
+/// What an http tunnel forwards to
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    /// Speak https to the upstream
+    pub tls: bool,
+    /// Accept any certificate, for dev servers with a self-signed one
+    pub insecure: bool,
+    /// Host header to send instead of the public host of the tunnel
+    pub host_header: Option<String>,
+    /// Point `Origin` and `Referer` headers at the public host to `host_header` too
+    pub rewrite_origin: bool,
+    /// Pass the public host and scheme on in `X-Forwarded-*` and `Forwarded`
+    pub forwarded_headers: bool,
+}
+
+impl Upstream {
+    /// Value for the host header, without the port when it is the default one
+    pub fn authority(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => host,
+            _ => format!("{host}:{}", self.port),
+        }
+    }
+}
+
+/// What the eaves proxies of all tunnels share
+#[derive(Clone)]
+pub struct SharedState {
+    pub traffic_store: Arc<RwLock<TrafficStore>>,
+    pub live_traffic: broadcast::Sender<RequestCycle>,
+    /// Request bodies are captured up to this size, the rest is only forwarded
+    pub max_body_bytes: usize,
+    pub breakpoints: Arc<Mutex<Breakpoints>>,
+    /// Mocks and rewrites from the config
+    pub rules: Arc<[ProxyRule]>,
+}
+
 pub struct EavesProxy {
-    traffic_log: Arc<RwLock<TrafficLog>>,
-    target_port: u16,
+    upstream: Upstream,
+    ports: TunnelPorts,
+    /// Url the server gave the tunnel, set once the client is connected
+    public_url: Arc<RwLock<Option<String>>>,
+    shared: SharedState,
 }
 
 pub struct MyCtx {
     pub timestamp_in: Option<chrono::DateTime<Utc>>,
+    /// When the request came in, the timings are measured from here
+    pub started: Option<Instant>,
+    pub timings: Timings,
+    pub sizes: BodySizes,
     pub request_head: Option<RequestHead>,
     pub request_body: Vec<u8>,
     pub timestamp_out: Option<chrono::DateTime<Utc>>,
     pub response_head: Option<ResponseHead>,
     pub response_body: Vec<u8>,
+    /// The exchange was dropped at a breakpoint
+    pub dropped: bool,
+    /// Names of the rules that answered or rewrote the exchange
+    pub rules: Vec<String>,
+    /// Body a rewrite rule sends instead of the one from the local service
+    pub replacement_body: Option<Bytes>,
+    /// Request body read at a breakpoint, it goes on in place of the copy pingora sends
+    pub held_request_body: Option<Bytes>,
+}
+
+impl MyCtx {
+    fn micros_since_start(&self) -> Option<u64> {
+        self.started
+            .map(|started| started.elapsed().as_micros() as u64)
+    }
 }
 
 fn header_mapper((name, val): (&HeaderName, &HeaderValue)) -> (String, String) {
@@ -33,49 +116,435 @@ fn header_mapper((name, val): (&HeaderName, &HeaderValue)) -> (String, String) {
     )
 }
 
+/// Sort the error that ended an exchange into the outcomes the UI shows
+fn outcome_of(e: &Error) -> Outcome {
+    let reason = e.to_string();
+    match (e.etype(), e.esource()) {
+        (
+            ErrorType::ConnectTimedout
+            | ErrorType::ReadTimedout
+            | ErrorType::WriteTimedout
+            | ErrorType::TLSHandshakeTimedout,
+            _,
+        ) => Outcome::Timeout(reason),
+        (
+            ErrorType::ConnectRefused
+            | ErrorType::ConnectNoRoute
+            | ErrorType::ConnectError
+            | ErrorType::TLSHandshakeFailure,
+            _,
+        ) => Outcome::UpstreamConnect(reason),
+        (_, ErrorSource::Downstream) => Outcome::ClientAbort(reason),
+        _ => Outcome::ProxyError(reason),
+    }
+}
+
+fn header_value(head: &RequestHeader, name: &str) -> Option<String> {
+    head.headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Scheme and host of the public url of a tunnel, `None` for anything that could
+/// not go into a `Forwarded` header as it is
+fn public_origin(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let host = rest.split('/').next()?;
+    let valid = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+    };
+    (valid(scheme) && valid(host)).then_some((scheme, host))
+}
+
+/// Move a url pointing at `from_host` over to `to_host`, urls pointing elsewhere are left alone
+fn rebase_url(url: &str, from_host: &str, scheme: &str, to_host: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    host.eq_ignore_ascii_case(from_host)
+        .then(|| format!("{scheme}://{to_host}{path}"))
+}
+
+fn invalid_edit<E>(_: E) -> Box<Error> {
+    Error::explain(ErrorType::InternalError, "invalid head from a breakpoint")
+}
+
+/// Make the request pingora passes on match the head edited at a breakpoint
+fn apply_request_head(head: &mut RequestHeader, edited: &RequestHead) -> Result<()> {
+    head.set_method(edited.method.parse().map_err(invalid_edit)?);
+    head.set_uri(edited.uri.parse().map_err(invalid_edit)?);
+    // Removing every header by name keeps the case pingora remembers for them in sync
+    let names: Vec<HeaderName> = head.headers.keys().cloned().collect();
+    for name in names {
+        head.remove_header(&name);
+    }
+    for (name, value) in &edited.headers {
+        head.append_header(name.clone(), value.as_str())?;
+    }
+    Ok(())
+}
+
+/// Make the response pingora passes on match the head edited at a breakpoint
+fn apply_response_head(head: &mut ResponseHeader, edited: &ResponseHead) -> Result<()> {
+    head.set_status(edited.status)?;
+    let names: Vec<HeaderName> = head.headers.keys().cloned().collect();
+    for name in names {
+        head.remove_header(&name);
+    }
+    for (name, value) in &edited.headers {
+        head.append_header(name.clone(), value.as_str())?;
+    }
+    Ok(())
+}
+
+impl EavesProxy {
+    fn capture_request_body(&self, chunk: &[u8], ctx: &mut MyCtx) {
+        ctx.sizes.request += chunk.len() as u64;
+        let room = self
+            .shared
+            .max_body_bytes
+            .saturating_sub(ctx.request_body.len());
+        ctx.request_body
+            .extend_from_slice(&chunk[..min(room, chunk.len())]);
+    }
+
+    /// Answer with the canned response of a rule, the local service never sees
+    /// the request. Its body is still read, so it shows up in the inspector.
+    async fn respond_with_mock(
+        &self,
+        session: &mut Session,
+        name: &str,
+        mock: &MockResponse,
+        ctx: &mut MyCtx,
+    ) -> Result<()> {
+        if let Some(held) = ctx.held_request_body.clone() {
+            self.capture_request_body(&held, ctx);
+        }
+        while let Some(chunk) = session.read_request_body().await? {
+            self.capture_request_body(&chunk, ctx);
+        }
+        let head = mock.head();
+        let mut header = ResponseHeader::build(head.status, Some(head.headers.len()))?;
+        for (name, value) in &head.headers {
+            header.append_header(name.clone(), value.as_str())?;
+        }
+        ctx.timings.first_byte = ctx.micros_since_start();
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(mock.body.clone())), true)
+            .await?;
+        ctx.timings.done = ctx.micros_since_start();
+        ctx.timestamp_out = Some(Utc::now());
+        ctx.sizes.response = mock.body.len() as u64;
+        ctx.response_head = Some(head);
+        ctx.response_body = mock.body.clone().into_bytes();
+        ctx.rules.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Read the whole request body ahead of a breakpoint, so it can be edited.
+    /// `None` when there is none to pause or the body is chunked or too big,
+    /// then it streams through as usual.
+    async fn hold_request_body(
+        &self,
+        session: &mut Session,
+        request_head: &RequestHead,
+    ) -> Result<Option<Vec<u8>>> {
+        if !self
+            .shared
+            .breakpoints
+            .lock()
+            .wants(Phase::Request, request_head)
+        {
+            return Ok(None);
+        }
+        let fits = session.is_body_empty()
+            || session
+                .req_header()
+                .headers
+                .get("content-length")
+                .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+                .is_some_and(|length| length <= MAX_HELD_BODY);
+        if !fits {
+            return Ok(None);
+        }
+        // Whatever is read from here on is sent on from the retry buffer
+        session.enable_retry_buffering();
+        let mut body = vec![];
+        while let Some(chunk) = session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Some(body))
+    }
+
+    /// Hold the exchange while a breakpoint wants it paused, `None` when it can
+    /// go on as it is
+    async fn wait_at_breakpoint(
+        &self,
+        phase: Phase,
+        request_head: &RequestHead,
+        request_body: Option<Vec<u8>>,
+        response_head: Option<&ResponseHead>,
+    ) -> Option<Verdict> {
+        let (id, verdict) = self.shared.breakpoints.lock().pause(
+            phase,
+            request_head,
+            request_body,
+            response_head,
+            self.ports.clone(),
+        )?;
+        match tokio::time::timeout(MAX_PAUSE, verdict).await {
+            Ok(verdict) => verdict.ok(),
+            Err(_) => {
+                log::info!("releasing exchange {id}, nobody decided on it in time");
+                self.shared.breakpoints.lock().forget(id);
+                None
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl ProxyHttp for EavesProxy {
     type CTX = MyCtx;
     fn new_ctx(&self) -> Self::CTX {
         MyCtx {
             timestamp_in: None,
+            started: None,
+            timings: Timings::default(),
+            sizes: BodySizes::default(),
             request_head: None,
             request_body: vec![],
             timestamp_out: None,
             response_head: None,
             response_body: vec![],
+            dropped: false,
+            rules: vec![],
+            replacement_body: None,
+            held_request_body: None,
         }
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
         ctx.timestamp_in = Some(Utc::now());
-        if let Ok(Some(b)) = session.read_request_body().await {
-            ctx.request_body = b.into()
-        };
+        ctx.started = Some(Instant::now());
         let head = session.req_header();
-        ctx.request_head = Some(RequestHead {
+        let request_head = RequestHead {
             method: head.method.as_str().into(),
             uri: head.uri.to_string(),
             headers: head.headers.iter().map(header_mapper).collect::<Vec<_>>(),
-        });
+        };
+        let held_body = self.hold_request_body(session, &request_head).await?;
+        ctx.held_request_body = held_body.clone().map(Bytes::from);
+        match self
+            .wait_at_breakpoint(Phase::Request, &request_head, held_body, None)
+            .await
+        {
+            Some(Verdict::ReleaseRequest {
+                head: mut edited,
+                body,
+            }) => {
+                // Only a body that was held can be swapped, others are on their way already.
+                // One left as it was keeps its encoding.
+                let held = ctx.held_request_body.as_deref();
+                if let Some(body) = body.filter(|body| held.is_some_and(|held| held != body)) {
+                    fit_to_body(&mut edited.headers, &body);
+                    ctx.held_request_body = Some(Bytes::from(body));
+                }
+                apply_request_head(session.req_header_mut(), &edited)?;
+                ctx.request_head = Some(edited);
+            }
+            Some(Verdict::Drop) => {
+                if let Some(held) = ctx.held_request_body.clone() {
+                    self.capture_request_body(&held, ctx);
+                }
+                ctx.request_head = Some(request_head);
+                ctx.dropped = true;
+                session.respond_error(502).await?;
+                return Ok(true);
+            }
+            _ => ctx.request_head = Some(request_head),
+        }
+        let mock = ctx
+            .request_head
+            .as_ref()
+            .and_then(|head| mock_for(&self.shared.rules, head));
+        if let Some((name, mock)) = mock {
+            self.respond_with_mock(session, name, mock, ctx).await?;
+            return Ok(true);
+        }
         Ok(false)
     }
 
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(held) = &ctx.held_request_body {
+            // Pingora sends its own copy of the body read at the breakpoint in one go
+            *body = Some(held.clone());
+        }
+        if let Some(b) = body {
+            self.capture_request_body(b, ctx);
+        }
+        Ok(())
+    }
+
     async fn upstream_peer(&self, _: &mut Session, _ctx: &mut Self::CTX) -> Result<Box<HttpPeer>> {
-        let addr = ("127.0.0.1", self.target_port);
-        Ok(Box::new(HttpPeer::new(addr, false, "nada".to_string())))
+        let upstream = &self.upstream;
+        // Resolved here, pingora would resolve it blocking and panic when it fails
+        let addr = tokio::net::lookup_host((upstream.host.as_str(), upstream.port))
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| {
+                Error::explain(
+                    ErrorType::ConnectNoRoute,
+                    format!("could not resolve {}", upstream.host),
+                )
+            })?;
+        // SNI only carries DNS names. Without one pingora skips verifying the
+        // certificate, which could not name an ip address anyway
+        let sni = match upstream.host.parse::<IpAddr>() {
+            Ok(_) => String::new(),
+            Err(_) => upstream.host.clone(),
+        };
+        let mut peer = HttpPeer::new(addr, upstream.tls, sni);
+        if upstream.insecure {
+            peer.options.verify_cert = false;
+            peer.options.verify_hostname = false;
+        }
+        Ok(Box::new(peer))
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // The visitor picks the host header, the public host is the one the server gave out
+        let public_url = self.public_url.read().clone();
+        let public = public_url.as_deref().and_then(public_origin);
+        if let (true, Some((proto, public_host))) = (self.upstream.forwarded_headers, public) {
+            let mut forwarded = format!("host=\"{public_host}\";proto={proto}");
+            // The server puts the visitor first in X-Forwarded-For
+            if let Some(visitor) = header_value(upstream_request, "x-forwarded-for")
+                .as_deref()
+                .and_then(|ips| ips.split(',').next())
+                .map(str::trim)
+            {
+                match visitor.contains(':') {
+                    true => forwarded = format!("for=\"[{visitor}]\";{forwarded}"),
+                    false => forwarded = format!("for={visitor};{forwarded}"),
+                }
+            }
+            upstream_request.insert_header("x-forwarded-host", public_host)?;
+            upstream_request.insert_header("x-forwarded-proto", proto)?;
+            upstream_request.insert_header("forwarded", forwarded)?;
+        }
+        let Some(host_header) = &self.upstream.host_header else {
+            return Ok(());
+        };
+        if let (true, Some((_, public_host))) = (self.upstream.rewrite_origin, public) {
+            let scheme = match self.upstream.tls {
+                true => "https",
+                false => "http",
+            };
+            for name in ["origin", "referer"] {
+                let rebased = header_value(upstream_request, name)
+                    .and_then(|url| rebase_url(&url, public_host, scheme, host_header));
+                if let Some(rebased) = rebased {
+                    upstream_request.insert_header(name, rebased)?;
+                }
+            }
+        }
+        upstream_request.insert_header("host", host_header.as_str())?;
+        Ok(())
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        _reused: bool,
+        _peer: &HttpPeer,
+        _fd: RawFd,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.timings.connected = ctx.micros_since_start();
+        Ok(())
     }
 
     async fn response_filter(
         &self,
         _session: &mut Session,
-        head: &mut pingora_http::ResponseHeader,
+        head: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
-        ctx.response_head = Some(ResponseHead {
+        ctx.timings.first_byte = ctx.micros_since_start();
+        let mut response_head = ResponseHead {
             status: head.status.into(),
             headers: head.headers.iter().map(header_mapper).collect::<Vec<_>>(),
-        });
+        };
+        if let Some(request_head) = &ctx.request_head {
+            for (name, rewrite) in rewrites_for(&self.shared.rules, request_head) {
+                rewrite.apply(&mut response_head);
+                if let Some(body) = &rewrite.body {
+                    ctx.replacement_body = Some(Bytes::from(body.clone()));
+                }
+                ctx.rules.push(name.to_owned());
+            }
+        }
+        if !ctx.rules.is_empty() {
+            apply_response_head(head, &response_head)?;
+        }
+        let verdict = match &ctx.request_head {
+            Some(request_head) => {
+                self.wait_at_breakpoint(Phase::Response, request_head, None, Some(&response_head))
+                    .await
+            }
+            None => None,
+        };
+        match verdict {
+            Some(Verdict::ReleaseResponse {
+                head: mut edited,
+                body,
+            }) => {
+                if let Some(body) = body {
+                    fit_to_body(&mut edited.headers, &body);
+                    ctx.replacement_body = Some(Bytes::from(body));
+                }
+                apply_response_head(head, &edited)?;
+                ctx.response_head = Some(edited);
+            }
+            Some(Verdict::Drop) => {
+                ctx.response_head = Some(response_head);
+                ctx.dropped = true;
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(502),
+                    "dropped at a breakpoint",
+                ));
+            }
+            _ => ctx.response_head = Some(response_head),
+        }
         Ok(())
     }
 
@@ -89,36 +558,91 @@ impl ProxyHttp for EavesProxy {
     where
         Self::CTX: Send + Sync,
     {
+        if let Some(replacement) = &ctx.replacement_body {
+            // What the local service sends is swallowed, the replacement goes out at the end
+            *body = end_of_stream.then(|| replacement.clone());
+        }
         if let Some(b) = body {
+            ctx.sizes.response += b.len() as u64;
             ctx.response_body.extend(&b[..]);
         }
         if end_of_stream {
-            self.traffic_log.write().requests.push(RequestCycle {
-                timestamp_in: ctx.timestamp_in.unwrap(),
-                request_head: ctx.request_head.take().unwrap(),
-                request_body: std::mem::take(&mut ctx.request_body),
-                timestamp_out: Utc::now(),
-                response_head: ctx.response_head.take().unwrap(),
-                response_body: std::mem::take(&mut ctx.response_body),
-            });
+            ctx.timestamp_out = Some(Utc::now());
+            ctx.timings.done = ctx.micros_since_start();
         }
 
         Ok(None)
     }
+
+    /// Called when an exchange is over, however it ended. Failed exchanges are
+    /// recorded too, with whatever made it through before things went wrong.
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+        // Requests that never made it past parsing have nothing to show
+        let (timestamp_in, request_head) = match (ctx.timestamp_in, ctx.request_head.take()) {
+            (Some(timestamp_in), Some(request_head)) => (timestamp_in, request_head),
+            _ => return,
+        };
+        // Without a response from upstream, record what the proxy answered itself
+        let response_head = ctx.response_head.take().unwrap_or_else(|| ResponseHead {
+            status: session
+                .response_written()
+                .map_or(0, |head| head.status.as_u16()),
+            headers: vec![],
+        });
+        let mut traffic_store = self.shared.traffic_store.write();
+        let cycle = RequestCycle {
+            id: traffic_store.next_id(),
+            timestamp_in,
+            request_head,
+            request_body: std::mem::take(&mut ctx.request_body),
+            timestamp_out: ctx.timestamp_out.unwrap_or_else(Utc::now),
+            response_head,
+            response_body: std::mem::take(&mut ctx.response_body),
+            ports: self.ports.clone(),
+            outcome: match ctx.dropped {
+                true => Outcome::Dropped,
+                false => e.map_or(Outcome::Completed, outcome_of),
+            },
+            timings: Timings {
+                // A failed exchange is done when it is given up on
+                done: ctx.timings.done.or_else(|| ctx.micros_since_start()),
+                ..ctx.timings
+            },
+            sizes: ctx.sizes,
+            rules: std::mem::take(&mut ctx.rules),
+        };
+        if traffic_store.push(cycle.clone()) {
+            // Sending only fails when no UI is listening, which is fine
+            let _ = self.shared.live_traffic.send(cycle);
+        }
+    }
 }
 
 pub fn configure_eaves_proxy(
     conf: &Arc<pingora::server::configuration::ServerConf>,
-    target_port: u16,
-    traffic_log: Arc<RwLock<TrafficLog>>,
-) -> (Service<HttpProxy<EavesProxy>>, u16) {
+    upstream: Upstream,
+    proxy_port: u16,
+    public_url: Arc<RwLock<Option<String>>>,
+    shared: SharedState,
+) -> Service<HttpProxy<EavesProxy>> {
     let mut my_proxy = pingora_proxy::http_proxy_service(
         conf,
         EavesProxy {
-            traffic_log,
-            target_port,
+            ports: TunnelPorts {
+                target_port: upstream.port,
+                proxy_port,
+                target_host: upstream.host.clone(),
+                target_tls: upstream.tls,
+                insecure_target: upstream.insecure,
+            },
+            upstream,
+            public_url,
+            shared,
         },
     );
-    my_proxy.add_tcp("127.0.0.1:6190");
-    (my_proxy, 6190)
+    my_proxy.add_tcp(&format!("127.0.0.1:{proxy_port}"));
+    my_proxy
 }
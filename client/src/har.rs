@@ -0,0 +1,69 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use clap::Subcommand;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use leptos::get_configuration;
+
+#[derive(Subcommand)]
+pub enum HarCommand {
+    /// Download the traffic a running client captured as a HAR file
+    Export {
+        /// File to write to, stdout when not given
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Load a HAR file into the inspector of a running client, to replay it from there
+    Import { file: PathBuf },
+}
+
+/// Talk to the inspector of a running client, it knows about the captured traffic
+pub fn run(command: &HarCommand) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let conf = get_configuration(None).await?;
+        let url = format!("http://{}{}", conf.leptos_options.site_addr, app::HAR_PATH);
+        let client = reqwest::Client::new();
+        match command {
+            HarCommand::Export { output } => {
+                let har = client
+                    .get(&url)
+                    .send()
+                    .await
+                    .wrap_err_with(|| {
+                        format!("Could not reach the inspector at {url}, is sgrok running?")
+                    })?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                match output {
+                    Some(path) => fs::write(path, &har)
+                        .wrap_err_with(|| format!("Could not write {path:?}"))?,
+                    None => io::stdout().write_all(&har)?,
+                }
+            }
+            HarCommand::Import { file } => {
+                let har = fs::read(file).wrap_err_with(|| format!("Could not read {file:?}"))?;
+                let response = client
+                    .post(&url)
+                    .header("content-type", "application/json")
+                    .body(har)
+                    .send()
+                    .await
+                    .wrap_err_with(|| {
+                        format!("Could not reach the inspector at {url}, is sgrok running?")
+                    })?;
+                let status = response.status();
+                let message = response.text().await?;
+                if !status.is_success() {
+                    bail!("Import failed: {}", message.trim());
+                }
+                log::info!("{}", message.trim());
+            }
+        }
+        Ok(())
+    })
+}
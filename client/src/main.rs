@@ -1,48 +1,385 @@
-use std::sync::Arc;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use clap::{Parser, ValueEnum};
-use parking_lot::RwLock;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{Result, WrapErr};
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use tokio::sync::broadcast;
 
-use shared_types::TrafficLog;
+use app::{breakpoints::Breakpoints, store::TrafficStore};
+use shared_types::{
+    handshake::{AccessPolicy, BasicAuth, LoginGate},
+    TunnelPorts,
+};
 
 pub mod eaves_proxy;
+pub mod har;
+pub mod settings;
 pub mod sgclient;
+pub mod snippet;
 pub mod ui;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
-enum Mode {
+/// How many request cycles a slow UI may fall behind on the live traffic stream
+const LIVE_TRAFFIC_CAPACITY: usize = 256;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
     Http,
     Tcp,
 }
 
+/// Version of the PROXY protocol header to start connections to the target of a tcp tunnel with
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 pub struct Cli {
-    /// What mode to run the program in
-    #[clap(value_enum, value_parser)]
-    mode: Mode,
+    #[clap(subcommand)]
+    command: Command,
+    #[clap(long, short, action, global = true)]
+    dev: bool,
+    /// Config file to read on top of the user config and `sgrok.toml` in the working directory
+    #[clap(long, short, global = true)]
+    config: Option<PathBuf>,
+    /// Storm grok server to connect to as `host[:port]`, write ipv6 addresses as `[::1]:5000`
+    #[clap(long, global = true, value_parser = parse_server_address)]
+    server: Option<ServerAddress>,
+    /// Name to verify the server's certificate against, when it differs from the server host
+    #[clap(long, global = true)]
+    sni: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct ServerAddress {
+    host: String,
+    port: Option<u16>,
+}
+
+fn parse_server_address(address: &str) -> Result<ServerAddress, String> {
+    if let Ok(socket_addr) = address.parse::<SocketAddr>() {
+        return Ok(ServerAddress {
+            host: socket_addr.ip().to_string(),
+            port: Some(socket_addr.port()),
+        });
+    }
+    let bare = address.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Ok(ServerAddress {
+            host: ip.to_string(),
+            port: None,
+        });
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => Ok(ServerAddress {
+                host: host.to_owned(),
+                port: Some(port),
+            }),
+            Err(_) => Err(format!("invalid port in `{address}`")),
+        },
+        None => Ok(ServerAddress {
+            host: address.to_owned(),
+            port: None,
+        }),
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Expose a local http service, its traffic shows up in the inspector
+    Http(TunnelArgs),
+    /// Expose a local tcp service
+    Tcp(TunnelArgs),
+    /// Open tunnels defined in the config file by name, or all of them when no name is given
+    Start { names: Vec<String> },
+    /// Move captured traffic in and out of a running client as HAR files
+    Har {
+        #[clap(subcommand)]
+        command: har::HarCommand,
+    },
+    /// Print a captured request of a running client as a curl, httpie, fetch or reqwest snippet
+    Snippet(snippet::SnippetArgs),
+}
+
+#[derive(Args)]
+struct TunnelArgs {
     /// Port to forward to
-    #[clap(value_parser = clap::value_parser!(u16).range(1..65536))]
+    #[clap(
+        value_parser = clap::value_parser!(u16).range(1..65536),
+        required_unless_present = "to"
+    )]
+    target_port: Option<u16>,
+    /// Forward to another host than localhost as `host[:port]`, e.g. `db.internal:5432`
+    #[clap(long, value_parser = parse_server_address)]
+    to: Option<ServerAddress>,
+    /// Speak https to the target of the http tunnel
+    #[clap(long, action)]
+    upstream_tls: bool,
+    /// Accept any certificate from the target, for dev servers with a self-signed one
+    #[clap(long, action, requires = "upstream_tls")]
+    insecure_upstream: bool,
+    /// Host header to send to the target, `rewrite` sends the target's own host and port
+    #[clap(long)]
+    host_header: Option<String>,
+    /// Point `Origin` and `Referer` at the host header too, when they point at the tunnel
+    #[clap(long, action, requires = "host_header")]
+    rewrite_origin: bool,
+    /// Pass the public host and scheme on in `X-Forwarded-*` and `Forwarded` headers
+    #[clap(long, action)]
+    forwarded_headers: bool,
+    /// Tell the target of the tcp tunnel who connected with a PROXY protocol header
+    #[clap(long)]
+    proxy_protocol: Option<ProxyProtocol>,
+    /// Subdomain to request for an http tunnel, e.g. `my-pr-123`
+    #[clap(long, short)]
+    subdomain: Option<String>,
+    /// Extra tunnel over the same connection as `mode:port[:subdomain]`, e.g. `tcp:5432`,
+    /// the access flags below apply to it too
+    #[clap(long = "tunnel", short, value_parser = parse_tunnel_spec)]
+    tunnels: Vec<TunnelSpec>,
+    /// Make visitors of the http tunnel log in with basic auth as `user:password`
+    #[clap(long, value_parser = parse_basic_auth)]
+    basic_auth: Option<BasicAuth>,
+    /// Only let visitors in from this network, e.g. `10.0.0.0/8`, can be repeated
+    #[clap(long = "allow-cidr")]
+    allow_cidrs: Vec<String>,
+    /// Make visitors of the http tunnel log in with a token the server trusts
+    #[clap(long, action)]
+    login: bool,
+    /// Only let this user (email or token subject) in after logging in, implies `--login`
+    #[clap(long = "allow-user")]
+    allow_users: Vec<String>,
+}
+
+impl TunnelArgs {
+    fn access(&self) -> AccessPolicy {
+        AccessPolicy {
+            basic_auth: self.basic_auth.clone(),
+            allow_cidrs: self.allow_cidrs.clone(),
+            login: (self.login || !self.allow_users.is_empty()).then(|| LoginGate {
+                users: self.allow_users.clone(),
+            }),
+        }
+    }
+}
+
+fn parse_basic_auth(credentials: &str) -> Result<BasicAuth, String> {
+    match credentials.split_once(':') {
+        Some((username, password)) if !username.is_empty() => Ok(BasicAuth {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }),
+        _ => Err("expected `user:password`".into()),
+    }
+}
+
+impl Cli {
+    /// Command line flags win from anything in config
+    fn apply_overrides(&self, settings: &mut settings::Settings) {
+        if self.dev {
+            settings.server.host = "127.0.0.1".into();
+            settings.server.sni = Some("localhost".into());
+        }
+        if let Some(server) = &self.server {
+            settings.server.host = server.host.clone();
+            if let Some(port) = server.port {
+                settings.server.quic_port = port;
+            }
+        }
+        if let Some(sni) = &self.sni {
+            settings.server.sni = Some(sni.clone());
+        }
+    }
+
+    /// All tunnels to open, the one given by the positional arguments first
+    fn tunnel_specs(&self, settings: &settings::Settings) -> Result<Vec<TunnelSpec>, String> {
+        let (mode, args) = match &self.command {
+            Command::Http(args) => (Mode::Http, args),
+            Command::Tcp(args) => (Mode::Tcp, args),
+            Command::Start { names } if names.is_empty() => {
+                return match settings.tunnels.is_empty() {
+                    true => Err("there are no tunnels defined in your config".into()),
+                    false => Ok(settings.tunnels.values().map(TunnelSpec::from).collect()),
+                }
+            }
+            Command::Start { names } => {
+                return names
+                    .iter()
+                    .map(|name| match settings.tunnels.get(name) {
+                        Some(tunnel) => Ok(TunnelSpec::from(tunnel)),
+                        None => Err(format!(
+                            "no tunnel named `{name}` in your config, known tunnels are: {:?}",
+                            settings.tunnels.keys().collect::<Vec<_>>()
+                        )),
+                    })
+                    .collect()
+            }
+            Command::Har { .. } | Command::Snippet(_) => {
+                unreachable!("inspector commands do not open tunnels")
+            }
+        };
+        let target_port = match args.to.as_ref().and_then(|to| to.port) {
+            Some(port) => port,
+            None => args.target_port.ok_or(
+                "give the port to forward to, as the target port or with `--to host:port`",
+            )?,
+        };
+        if mode == Mode::Tcp && args.upstream_tls {
+            return Err(
+                "`--upstream-tls` is for http tunnels, tcp tunnels pass tls on as is".into(),
+            );
+        }
+        if mode == Mode::Http && args.proxy_protocol.is_some() {
+            return Err(
+                "`--proxy-protocol` is for tcp tunnels, http tunnels get `X-Forwarded-For`".into(),
+            );
+        }
+        let primary = TunnelSpec {
+            mode,
+            target_host: args.to.as_ref().map(|to| to.host.clone()),
+            target_port,
+            upstream_tls: args.upstream_tls,
+            insecure_upstream: args.insecure_upstream,
+            host_header: args.host_header.clone(),
+            rewrite_origin: args.rewrite_origin,
+            forwarded_headers: args.forwarded_headers,
+            proxy_protocol: args.proxy_protocol,
+            subdomain: args.subdomain.clone(),
+            access: args.access(),
+        };
+        let mut tunnels = vec![primary];
+        // The access flags guard the extra tunnels as well, none is left open by accident
+        for tunnel in &args.tunnels {
+            tunnels.push(TunnelSpec {
+                access: tunnels[0].access.clone(),
+                ..tunnel.clone()
+            });
+        }
+        Ok(tunnels)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TunnelSpec {
+    mode: Mode,
+    /// Host to forward to, localhost when not given
+    target_host: Option<String>,
     target_port: u16,
-    #[clap(long, short, action)]
-    dev: bool,
+    upstream_tls: bool,
+    insecure_upstream: bool,
+    /// Host header to send instead of the public one, `rewrite` for the target's own
+    host_header: Option<String>,
+    rewrite_origin: bool,
+    forwarded_headers: bool,
+    proxy_protocol: Option<ProxyProtocol>,
+    subdomain: Option<String>,
+    access: AccessPolicy,
 }
 
-impl From<Mode> for [u8; 1] {
-    fn from(mode: Mode) -> [u8; 1] {
+impl TunnelSpec {
+    fn target_host(&self) -> &str {
+        self.target_host.as_deref().unwrap_or("127.0.0.1")
+    }
+
+    /// Where the tunnel forwards to, for showing to the user
+    fn target(&self) -> String {
+        let host = self.target_host.as_deref().unwrap_or("localhost");
+        match host.contains(':') {
+            true => format!("[{host}]:{}", self.target_port),
+            false => format!("{host}:{}", self.target_port),
+        }
+    }
+}
+
+impl From<&settings::TunnelConfig> for TunnelSpec {
+    fn from(tunnel: &settings::TunnelConfig) -> Self {
+        TunnelSpec {
+            mode: tunnel.mode,
+            target_host: tunnel.host.clone(),
+            target_port: tunnel.port,
+            upstream_tls: tunnel.upstream_tls,
+            insecure_upstream: tunnel.insecure_upstream,
+            host_header: tunnel.host_header.clone(),
+            rewrite_origin: tunnel.rewrite_origin,
+            forwarded_headers: tunnel.forwarded_headers,
+            proxy_protocol: tunnel.proxy_protocol,
+            subdomain: tunnel.subdomain.clone(),
+            access: tunnel.access.clone(),
+        }
+    }
+}
+
+fn parse_tunnel_spec(spec: &str) -> Result<TunnelSpec, String> {
+    let mut parts = spec.splitn(3, ':');
+    let mode = Mode::from_str(parts.next().unwrap_or_default(), true)?;
+    let target_port = match parts.next().map(str::parse::<u16>) {
+        Some(Ok(port)) if port > 0 => port,
+        _ => return Err(format!("expected `mode:port[:subdomain]`, got `{spec}`")),
+    };
+    Ok(TunnelSpec {
+        mode,
+        target_host: None,
+        target_port,
+        upstream_tls: false,
+        insecure_upstream: false,
+        host_header: None,
+        rewrite_origin: false,
+        forwarded_headers: false,
+        proxy_protocol: None,
+        subdomain: parts.next().map(str::to_owned),
+        access: AccessPolicy::default(),
+    })
+}
+
+impl From<Mode> for shared_types::handshake::Mode {
+    fn from(mode: Mode) -> Self {
         match mode {
-            Mode::Tcp => [b't'],
-            Mode::Http => [b'h'],
+            Mode::Tcp => Self::Tcp,
+            Mode::Http => Self::Http,
+        }
+    }
+}
+
+impl From<ProxyProtocol> for shared_types::proxy_protocol::Version {
+    fn from(version: ProxyProtocol) -> Self {
+        match version {
+            ProxyProtocol::V1 => Self::V1,
+            ProxyProtocol::V2 => Self::V2,
         }
     }
 }
 
-fn main() {
+/// Problems with the arguments are reported by clap with the usage, anything that
+/// goes wrong after that is an error of its own
+fn main() -> Result<()> {
+    color_eyre::install()?;
     simple_logger::init_with_level(log::Level::Info).expect("couldn't initialize logging");
 
     let cli = Cli::parse();
-    let target_port = cli.target_port;
-    let mode = cli.mode;
+    match &cli.command {
+        Command::Har { command } => return har::run(command),
+        Command::Snippet(args) => return snippet::run(args),
+        _ => {}
+    }
+    let mut settings =
+        settings::Settings::new(cli.config.as_deref()).wrap_err("could not read config")?;
+    cli.apply_overrides(&mut settings);
+    let tunnel_specs = match cli.tunnel_specs(&settings) {
+        Ok(tunnel_specs) => tunnel_specs,
+        Err(e) => Cli::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit(),
+    };
 
     // let demo_req = shared_types::RequestCycle {
     //     timestamp_in: chrono::Utc::now(),
@@ -59,28 +396,130 @@ fn main() {
     //     },
     //     response_body: Vec::new(),
     // };
-    let traffic_log: Arc<RwLock<TrafficLog>> =
-        Arc::new(RwLock::new(TrafficLog { requests: vec![] }));
+    let traffic_store =
+        TrafficStore::open(settings.traffic.clone()).wrap_err("could not open the traffic file")?;
+    let traffic_store = Arc::new(RwLock::new(traffic_store));
+    let (live_traffic, _) = broadcast::channel(LIVE_TRAFFIC_CAPACITY);
+    let breakpoints = Arc::new(Mutex::new(Breakpoints::default()));
+    let shared = eaves_proxy::SharedState {
+        traffic_store: traffic_store.clone(),
+        live_traffic: live_traffic.clone(),
+        max_body_bytes: settings.traffic.max_body_bytes,
+        breakpoints: breakpoints.clone(),
+        rules: settings.rules.clone().into(),
+    };
 
     let mut pingora_server = pingora::server::Server::new(None).unwrap();
     pingora_server.bootstrap();
     log::info!("bootstrapping pingora");
-    let ui_server = ui::configure_ui_client(traffic_log.clone());
-
-    if mode == Mode::Http {
-        let (eaves_proxy, proxy_port) = eaves_proxy::configure_eaves_proxy(
-            &pingora_server.configuration,
-            target_port,
-            traffic_log,
-        );
-        let sg_client = sgclient::configure_storm_grok_client(proxy_port, cli);
 
-        pingora_server.add_service(eaves_proxy);
-        pingora_server.add_service(sg_client);
-    } else {
-        let sg_client = sgclient::configure_storm_grok_client(target_port, cli);
-        pingora_server.add_service(sg_client);
+    // Every http tunnel gets its own eaves proxy, all logging to the same traffic log
+    let mut tunnels = Vec::new();
+    for (index, spec) in tunnel_specs.into_iter().enumerate() {
+        let public_url = Arc::new(RwLock::new(None));
+        let (forward_host, forward_port) = match spec.mode {
+            Mode::Http => {
+                let proxy_port = eaves_proxy::FIRST_PROXY_PORT + index as u16;
+                let mut upstream = eaves_proxy::Upstream {
+                    host: spec.target_host().to_owned(),
+                    port: spec.target_port,
+                    tls: spec.upstream_tls,
+                    insecure: spec.insecure_upstream,
+                    host_header: None,
+                    rewrite_origin: spec.rewrite_origin,
+                    forwarded_headers: spec.forwarded_headers,
+                };
+                upstream.host_header = match spec.host_header.as_deref() {
+                    Some("rewrite") => Some(upstream.authority()),
+                    Some(host) => Some(host.to_owned()),
+                    // Other machines tend to serve several sites, they need their own name to pick one
+                    None => spec.target_host.is_some().then(|| upstream.authority()),
+                };
+                let eaves_proxy = eaves_proxy::configure_eaves_proxy(
+                    &pingora_server.configuration,
+                    upstream,
+                    proxy_port,
+                    public_url.clone(),
+                    shared.clone(),
+                );
+                pingora_server.add_service(eaves_proxy);
+                ("127.0.0.1".to_owned(), proxy_port)
+            }
+            Mode::Tcp => (spec.target_host().to_owned(), spec.target_port),
+        };
+        tunnels.push(sgclient::Tunnel {
+            spec,
+            forward_host,
+            forward_port,
+            public_url,
+        });
     }
+    // Replays from the inspector can only go where these lead
+    let tunnel_ports: Arc<[TunnelPorts]> = tunnels
+        .iter()
+        .filter(|tunnel| tunnel.spec.mode == Mode::Http)
+        .map(|tunnel| TunnelPorts {
+            target_port: tunnel.spec.target_port,
+            proxy_port: tunnel.forward_port,
+            target_host: tunnel.spec.target_host().to_owned(),
+            target_tls: tunnel.spec.upstream_tls,
+            insecure_target: tunnel.spec.insecure_upstream,
+        })
+        .collect();
+    let ui_server = ui::configure_ui_client(
+        traffic_store.clone(),
+        live_traffic.clone(),
+        breakpoints,
+        tunnel_ports,
+    );
+    let sg_client = sgclient::configure_storm_grok_client(tunnels, cli.dev, settings);
+    pingora_server.add_service(sg_client);
     pingora_server.add_service(ui_server);
     pingora_server.run_forever();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> settings::Settings {
+        settings::Settings {
+            token: None,
+            server: settings::Server {
+                host: "127.0.0.1".into(),
+                quic_port: 5000,
+                sni: None,
+            },
+            tunnels: Default::default(),
+            traffic: Default::default(),
+            rules: vec![],
+        }
+    }
+
+    #[test]
+    fn access_flags_guard_the_extra_tunnels() {
+        let cli = Cli::try_parse_from([
+            "sgrok",
+            "http",
+            "3000",
+            "--tunnel",
+            "http:8080:api",
+            "--tunnel",
+            "tcp:5432",
+            "--allow-cidr",
+            "10.0.0.0/8",
+            "--allow-user",
+            "you@example.com",
+        ])
+        .unwrap();
+        let tunnels = cli.tunnel_specs(&settings()).unwrap();
+        assert_eq!(tunnels.len(), 3);
+        for tunnel in &tunnels {
+            assert_eq!(tunnel.access.allow_cidrs, vec!["10.0.0.0/8".to_string()]);
+            assert_eq!(
+                tunnel.access.login.as_ref().map(|gate| &gate.users[..]),
+                Some(&["you@example.com".to_string()][..])
+            );
+        }
+    }
+}
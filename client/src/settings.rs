@@ -0,0 +1,98 @@
+use app::store::StoreConfig;
+use config::{Config, ConfigError, Environment, File};
+use serde::Deserialize;
+use shared_types::{handshake::AccessPolicy, rules::ProxyRule};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{Mode, ProxyProtocol};
+
+/// Config file teams can commit to the root of their repo to share tunnel definitions
+const PROJECT_CONFIG_FILE: &str = "sgrok.toml";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Server {
+    /// Host name or ip address of the storm grok server
+    pub host: String,
+    pub quic_port: u16,
+    /// Name to verify the server's certificate against, defaults to `host`
+    pub sni: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TunnelConfig {
+    pub mode: Mode,
+    /// Host to forward to, localhost when not given
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Port to forward to
+    pub port: u16,
+    /// Speak https to the target of an http tunnel
+    #[serde(default)]
+    pub upstream_tls: bool,
+    /// Accept any certificate from the target, for dev servers with a self-signed one
+    #[serde(default)]
+    pub insecure_upstream: bool,
+    /// Host header to send instead of the public one, `rewrite` for the target's own
+    #[serde(default)]
+    pub host_header: Option<String>,
+    /// Point `Origin` and `Referer` at the host header too
+    #[serde(default)]
+    pub rewrite_origin: bool,
+    /// Pass the public host and scheme on in `X-Forwarded-*` and `Forwarded`
+    #[serde(default)]
+    pub forwarded_headers: bool,
+    /// Start connections to the target of a tcp tunnel with a PROXY protocol header
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocol>,
+    #[serde(default)]
+    pub subdomain: Option<String>,
+    /// Who may visit the tunnel, see `--basic-auth`, `--allow-cidr` and `--login`
+    #[serde(default)]
+    pub access: AccessPolicy,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Settings {
+    /// JWT to authenticate with, the `SGROK_TOKEN` env var takes precedence
+    pub token: Option<String>,
+    pub server: Server,
+    /// Named tunnels that can be opened with `start <name>..`
+    #[serde(default)]
+    pub tunnels: BTreeMap<String, TunnelConfig>,
+    /// How much captured traffic to keep around, and where
+    #[serde(default)]
+    pub traffic: StoreConfig,
+    /// Requests the eaves proxies answer themselves or whose responses they rewrite
+    #[serde(default)]
+    pub rules: Vec<ProxyRule>,
+}
+
+/// `~/.config/sgrok/config.toml` on linux, the platform's config dir elsewhere
+fn user_config_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sgrok").join("config.toml"))
+}
+
+impl Settings {
+    /// Layer the user config, the project config in the working directory, an
+    /// explicitly given config file and `SG__` prefixed env vars, in that order.
+    pub fn new(extra_config_file: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut builder = Config::builder()
+            .set_default("server.host", "stormgrok.nl")?
+            .set_default("server.quic_port", 5000)?;
+        if let Some(user_config_file) = user_config_file() {
+            builder = builder.add_source(File::from(user_config_file).required(false));
+        }
+        builder = builder.add_source(File::from(Path::new(PROJECT_CONFIG_FILE)).required(false));
+        if let Some(extra_config_file) = extra_config_file {
+            builder = builder.add_source(File::from(extra_config_file));
+        }
+        builder
+            .add_source(Environment::with_prefix("SG").separator("__"))
+            .set_override_option("token", std::env::var("SGROK_TOKEN").ok())?
+            .build()?
+            .try_deserialize()
+    }
+}
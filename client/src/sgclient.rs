@@ -1,29 +1,64 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result, WrapErr};
 use log::{debug, error, info, warn};
-use std::{env, io::ErrorKind, net::SocketAddr, sync::Arc};
+use std::{cmp::min, env, io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
 use uuid::Uuid;
 
+use parking_lot::RwLock;
 use quinn::ClientConfig;
 use rustls::KeyLogFile;
-use tokio::net::TcpStream;
+use shared_types::{
+    handshake::{
+        self, Accepted, Address, Capability, ClientHello, ClientMetadata, Options, ServerHello,
+        TunnelId, TunnelRequest,
+    },
+    proxy_protocol::{self, Decoded, Version},
+};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
 
 use quinn::{Connection, Endpoint, RecvStream, SendStream};
 
-use crate::{Cli, Mode};
+use crate::{settings, Mode, TunnelSpec};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Tunnel {
+    pub(crate) spec: TunnelSpec,
+    /// Where the streams of this tunnel are forwarded to, which is the local eaves
+    /// proxy for http tunnels and the target itself for tcp tunnels
+    pub(crate) forward_host: String,
+    pub(crate) forward_port: u16,
+    /// Url the server gave the tunnel, the eaves proxy passes it on to the target
+    pub(crate) public_url: Arc<RwLock<Option<String>>>,
+}
+
+/// Where the streams of a tunnel go, and whether they start with the visitor's address
+struct Forward {
+    host: String,
+    port: u16,
+    proxy_protocol: Option<Version>,
+}
 
 pub struct SgClient {
     dev: bool,
-    mode: Mode,
-    intermediate_target_port: u16,
-    final_target_port: u16,
+    settings: settings::Settings,
+    tunnels: Vec<Tunnel>,
+    /// Handed out by the server on every handshake, used to reclaim our addresses after a reconnect
+    resume_token: Option<Uuid>,
+    addresses: Option<Vec<Address>>,
 }
 
-pub fn configure_storm_grok_client(intermediate_target_port: u16, cli: Cli) -> SgClient {
+pub fn configure_storm_grok_client(
+    tunnels: Vec<Tunnel>,
+    dev: bool,
+    settings: settings::Settings,
+) -> SgClient {
     SgClient {
-        dev: cli.dev,
-        mode: cli.mode,
-        intermediate_target_port,
-        final_target_port: cli.target_port,
+        dev,
+        settings,
+        tunnels,
+        resume_token: None,
+        addresses: None,
     }
 }
 
@@ -36,51 +71,126 @@ impl pingora::services::Service for SgClient {
         shutdown: pingora::server::ShutdownWatch,
     ) {
         log::info!("starting service {} {:?}", self.name(), shutdown);
-        let mut endpoint = if self.dev {
-            setup_quic_on_available_port("127.0.0.1")
-        } else {
-            setup_quic_on_available_port("0.0.0.0")
-        };
-        let connection = start_quic_conn(&mut endpoint, self.dev)
-            .await
-            .unwrap()
+        let mut endpoint = None;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.run_session(&mut endpoint).await {
+                // The tunnel was up for a while, so start counting from scratch again
+                Ok(()) => backoff = MIN_BACKOFF,
+                Err(e) => error!("Could not connect to the storm grok server: {:#}", e),
+            }
+            info!("Reconnecting in {:?}", backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    fn name(&self) -> &str {
+        "storm grok client"
+    }
+}
+
+impl SgClient {
+    /// Connect to the server and serve the tunnels until the connection drops.
+    /// Only returns an error when the connection could not be set up at all.
+    async fn run_session(&mut self, endpoint: &mut Option<Endpoint>) -> Result<()> {
+        // Resolve on every attempt, the server may have moved while we were away
+        let server = &self.settings.server;
+        let remote = tokio::net::lookup_host((server.host.as_str(), server.quic_port))
             .await
-            .unwrap();
-        let response_bytes = sgrok_handshake(connection.clone(), self.mode).await;
+            .wrap_err_with(|| format!("Could not resolve {}", server.host))?
+            .next()
+            .ok_or_else(|| eyre!("{} did not resolve to any address", server.host))?;
+        let reusable = match endpoint {
+            Some(endpoint) => endpoint.local_addr()?.is_ipv4() == remote.is_ipv4(),
+            None => false,
+        };
+        if !reusable {
+            *endpoint = Some(setup_quic_endpoint_for(remote));
+        }
+        let endpoint = endpoint.as_mut().expect("endpoint was just set up");
+        let connection = start_quic_conn(endpoint, remote, server, self.dev)?.await?;
+        let tunnels = self
+            .tunnels
+            .iter()
+            .map(|tunnel| TunnelRequest {
+                mode: tunnel.spec.mode.into(),
+                subdomain: tunnel.spec.subdomain.clone(),
+                access: tunnel.spec.access.clone(),
+                peer_address: tunnel.spec.mode == Mode::Tcp && tunnel.spec.proxy_protocol.is_some(),
+            })
+            .collect();
+        let options = Options {
+            resume_token: self.resume_token,
+        };
+        let token = self.settings.token.clone();
+        let (capabilities, accepted) =
+            sgrok_handshake(connection.clone(), token, tunnels, options).await?;
+        self.resume_token = capabilities
+            .contains(&Capability::Resume)
+            .then_some(accepted.resume_token);
 
-        info!(
-            "Exposing localhost:{:?} on the internet!",
-            self.final_target_port
-        );
-        match self.mode {
-            Mode::Tcp => {
-                let port = u16::from_be_bytes(response_bytes.try_into().unwrap());
-                match self.dev {
-                    true => info!("nc localhost {:?}", port),
-                    false => info!("nc stormgrok.nl {:?}", port),
-                }
+        let addresses: Vec<Address> = accepted.tunnels.iter().map(|t| t.address.clone()).collect();
+        if self.addresses.as_ref() == Some(&addresses) {
+            info!("Reconnected and reclaimed our previous addresses!");
+        } else {
+            if self.addresses.is_some() {
+                warn!("Reconnected, but could not reclaim our previous addresses");
             }
-            Mode::Http => {
-                let http_server_port =
-                    env::var("SG__SERVER__HTTP_PORT").unwrap_or_else(|_| "3000".into());
-                let uuid = Uuid::from_bytes(response_bytes.try_into().unwrap());
-                match self.dev {
-                    true => info!("curl http://{:?}.localhost:{}", uuid, http_server_port),
-                    false => info!("curl https://{:?}.stormgrok.nl", uuid),
-                }
+            self.announce(&accepted);
+            self.addresses = Some(addresses);
+        }
+        let peer_addresses = capabilities.contains(&Capability::PeerAddress);
+        if !peer_addresses && self.tunnels.iter().any(|t| t.spec.proxy_protocol.is_some()) {
+            warn!(
+                "The server can not tell us who connects, no PROXY protocol headers will be sent"
+            );
+        }
+        self.serve(connection, peer_addresses).await;
+        Ok(())
+    }
+
+    fn announce(&self, accepted: &Accepted) {
+        for (tunnel, assignment) in self.tunnels.iter().zip(&accepted.tunnels) {
+            info!("Exposing {} on the internet!", tunnel.spec.target());
+            *tunnel.public_url.write() = Some(assignment.public_url.clone());
+            match assignment.address {
+                Address::Tcp { .. } => info!("Your tunnel is live at {}", assignment.public_url),
+                Address::Http { .. } => info!("curl {}", assignment.public_url),
             }
         }
+    }
+
+    async fn serve(&self, connection: Connection, peer_addresses: bool) {
+        let forwards: Arc<Vec<Forward>> = Arc::new(
+            self.tunnels
+                .iter()
+                .map(|t| Forward {
+                    host: t.forward_host.clone(),
+                    port: t.forward_port,
+                    proxy_protocol: match t.spec.mode {
+                        Mode::Tcp if peer_addresses => t.spec.proxy_protocol.map(Version::from),
+                        _ => None,
+                    },
+                })
+                .collect(),
+        );
         tokio::select!(
             _ = handle_uni_conns_loop(connection.clone()) => {},
-            _ = handle_bi_conns_loop(connection, self.intermediate_target_port) => {},
+            _ = handle_bi_conns_loop(connection, forwards) => {},
         );
-
-        endpoint.wait_idle().await;
     }
+}
 
-    fn name(&self) -> &str {
-        "storm grok client"
-    }
+/// Bind a quic endpoint that can reach `remote`, only on loopback when `remote` is local
+fn setup_quic_endpoint_for(remote: SocketAddr) -> Endpoint {
+    let host = match (remote.is_ipv4(), remote.ip().is_loopback()) {
+        (true, true) => "127.0.0.1",
+        (true, false) => "0.0.0.0",
+        (false, true) => "[::1]",
+        (false, false) => "[::]",
+    };
+    setup_quic_on_available_port(host)
 }
 
 fn setup_quic_on_available_port(host: &str) -> Endpoint {
@@ -104,18 +214,19 @@ fn setup_quic_on_available_port(host: &str) -> Endpoint {
     panic!("No ports available")
 }
 
-async fn start_quic_conn(endpoint: &mut Endpoint, dev_mode: bool) -> Result<quinn::Connecting> {
-    let quic_server_port: String =
-        env::var("SG__SERVER__QUIC_PORT").unwrap_or_else(|_| "5000".into());
-    if dev_mode {
+fn start_quic_conn(
+    endpoint: &mut Endpoint,
+    remote: SocketAddr,
+    server: &settings::Server,
+    insecure: bool,
+) -> Result<quinn::Connecting> {
+    let server_name = server.sni.as_deref().unwrap_or(&server.host);
+    if insecure {
         endpoint.set_default_client_config(configure_insecure_client());
-        let socket_addr = format!("127.0.0.1:{}", quic_server_port);
         info!(
-            "quic endpoint at {:?} configured for local, insecure connections only",
-            socket_addr
+            "quic endpoint at {:?} ({}) configured for insecure connections",
+            remote, server_name
         );
-        let t = socket_addr.parse::<SocketAddr>()?;
-        Ok(endpoint.connect(t, "localhost")?)
     } else {
         let mut root_store = rustls::RootCertStore::empty();
         root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
@@ -135,38 +246,65 @@ async fn start_quic_conn(endpoint: &mut Endpoint, dev_mode: bool) -> Result<quin
         client_config.key_log = Arc::new(rustls::KeyLogFile::new());
         let clc = quinn::ClientConfig::new(Arc::new(client_config));
         endpoint.set_default_client_config(clc);
-
-        let socket_addr = format!("157.90.124.255:{}", quic_server_port);
         info!(
-            "quic endpoint at {:?} onfigured for secure connections",
-            socket_addr
+            "quic endpoint at {:?} ({}) configured for secure connections",
+            remote, server_name
         );
-        Ok(endpoint.connect(socket_addr.parse::<SocketAddr>()?, "stormgrok.nl")?)
     }
+    Ok(endpoint.connect(remote, server_name)?)
 }
 
-async fn sgrok_handshake(conn: quinn::Connection, mode: Mode) -> Vec<u8> {
-    let (mut send, mut recv) = conn.open_bi().await.unwrap();
+/// Send a [`ClientHello`] with our tunnels, options and JWT to the server. Returns
+/// what the server can do and where our tunnels ended up.
+async fn sgrok_handshake(
+    conn: quinn::Connection,
+    token: Option<String>,
+    tunnels: Vec<TunnelRequest>,
+    options: Options,
+) -> Result<(Vec<Capability>, Accepted)> {
+    let (mut send, mut recv) = conn.open_bi().await?;
 
-    let token: String = match env::var("SGROK_TOKEN") {
-        Ok(token) => token,
-        Err(_) => {
-            warn!("You did not supply a JWT in the env var 'SGROK_TOKEN', using empty string to try and establish connection to server");
+    let token: String = match token {
+        Some(token) => token,
+        None => {
+            warn!("You did not supply a JWT in the env var 'SGROK_TOKEN' or in your config, using empty string to try and establish connection to server");
             "".to_string()
         }
     };
-    send.write_all(&<[u8; 1]>::from(mode)).await.unwrap();
-    send.write_all(token.as_bytes()).await.unwrap();
-    send.finish().await.unwrap();
+    let hello = ClientHello {
+        token,
+        tunnels,
+        options,
+        client: ClientMetadata {
+            name: env!("CARGO_PKG_NAME").into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            os: env::consts::OS.into(),
+        },
+    };
+    send.write_all(&handshake::encode(handshake::PROTOCOL_VERSION, &hello))
+        .await?;
+    send.finish().await?;
 
-    recv.read_to_end(16)
+    let response = recv
+        .read_to_end(handshake::MAX_FRAME_LEN)
         .await
-        .expect("The server did not give us a UUID!")
+        .wrap_err("The server did not answer our handshake!")?;
+    let (_, server_hello): (u16, ServerHello) = handshake::decode(&response)
+        .wrap_err("Could not understand the server, is it running a different version?")?;
+    debug!("Server capabilities: {:?}", server_hello.capabilities);
+    Ok((
+        server_hello.capabilities,
+        server_hello
+            .result
+            .wrap_err("The server refused our tunnel")?,
+    ))
 }
 
 async fn handle_uni_conns_loop(connection: Connection) {
     while let Ok(mut stream) = connection.accept_uni().await {
-        let buffered_data = stream.read_to_end(100).await.unwrap();
+        let Ok(buffered_data) = stream.read_to_end(100).await else {
+            break;
+        };
         if buffered_data != b"ping".to_vec() {
             info!(
                 "received from server: {:?}",
@@ -177,19 +315,48 @@ async fn handle_uni_conns_loop(connection: Connection) {
     error!("could net receive ping from server, something is wrong with the connection")
 }
 
-async fn handle_bi_conns_loop(connection: Connection, target_port: u16) {
+async fn handle_bi_conns_loop(connection: Connection, forwards: Arc<Vec<Forward>>) {
     while let Ok(streams) = connection.accept_bi().await {
         // Should I keep track of these spawned childtasks?
-        tokio::spawn(async move { handle_client_conn(streams, target_port).await });
+        let forwards = forwards.clone();
+        tokio::spawn(async move { handle_client_conn(streams, &forwards).await });
     }
     error!("error accepting bidirectional stream, something is wrong with the connection");
 }
 
-async fn handle_client_conn(streams: (SendStream, RecvStream), target_port: u16) {
+/// Every stream starts with the id of the tunnel it belongs to, after that it is
+/// piped to the address that tunnel forwards to. Tunnels that asked for it get
+/// the visitor's address next, which is passed on as a PROXY protocol header.
+async fn handle_client_conn(streams: (SendStream, RecvStream), forwards: &[Forward]) {
     let (mut client_send, mut client_recv) = streams;
-    match TcpStream::connect(("127.0.0.1", target_port)).await {
+    let mut tunnel_id = [0u8; 2];
+    if let Err(e) = client_recv.read_exact(&mut tunnel_id).await {
+        error!("Could not read tunnel id from stream: {:?}", e);
+        return;
+    }
+    let tunnel_id = TunnelId::from_be_bytes(tunnel_id);
+    let Some(forward) = forwards.get(tunnel_id as usize) else {
+        error!("Server sent a stream for unknown tunnel {:?}", tunnel_id);
+        return;
+    };
+    let proxy_header = match forward.proxy_protocol {
+        Some(version) => match read_peer_address(&mut client_recv).await {
+            Ok(addresses) => addresses.encode(version),
+            Err(e) => {
+                error!("Could not read the visitor's address from stream: {:#}", e);
+                return;
+            }
+        },
+        None => vec![],
+    };
+    let (target_host, target_port) = (&forward.host, forward.port);
+    match TcpStream::connect((target_host.as_str(), target_port)).await {
         Ok(server_stream) => {
             let (mut read_half, mut write_half) = server_stream.into_split();
+            if let Err(e) = write_half.write_all(&proxy_header).await {
+                error!("Could not send the PROXY protocol header: {:?}", e);
+                return;
+            }
             let yada = tokio::join!(
                 tokio::io::copy(&mut client_recv, &mut write_half),
                 tokio::io::copy(&mut read_half, &mut client_send),
@@ -197,12 +364,33 @@ async fn handle_client_conn(streams: (SendStream, RecvStream), target_port: u16)
             info!("Disconnected client! {:?}", yada);
         }
         Err(e) => {
-            error!("Encountered {:?} while connecting to {:?}", e, target_port);
+            error!(
+                "Encountered {:?} while connecting to {}:{}",
+                e, target_host, target_port
+            );
             client_send.finish().await.unwrap();
         }
     }
 }
 
+/// Read the version 2 PROXY protocol header the server puts after the tunnel id
+async fn read_peer_address(recv: &mut RecvStream) -> Result<proxy_protocol::Addresses> {
+    let mut header = vec![0u8; proxy_protocol::V2_FIXED_LEN];
+    recv.read_exact(&mut header).await?;
+    let fixed = header.first_chunk().expect("the fixed part was just read");
+    let len = proxy_protocol::v2_len(fixed);
+    header.resize(len, 0);
+    recv.read_exact(&mut header[proxy_protocol::V2_FIXED_LEN..])
+        .await?;
+    match proxy_protocol::decode(&header)? {
+        Decoded::Header {
+            addresses: Some(addresses),
+            ..
+        } => Ok(addresses),
+        _ => Err(eyre!("the server did not send the visitor's address")),
+    }
+}
+
 struct SkipServerVerification;
 
 impl SkipServerVerification {
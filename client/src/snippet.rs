@@ -0,0 +1,38 @@
+use clap::Args;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use leptos::get_configuration;
+use reqwest::StatusCode;
+use shared_types::{snippet::SnippetFormat, RequestCycle};
+
+#[derive(Args)]
+pub struct SnippetArgs {
+    /// Tool to write the snippet for: curl, httpie, fetch or reqwest
+    #[clap(long, short, default_value = "curl")]
+    format: SnippetFormat,
+    /// Id of the captured request, as shown in the inspector
+    #[clap(default_value = "latest")]
+    id: String,
+}
+
+/// Print a captured request of a running client as a snippet that sends it again
+pub fn run(args: &SnippetArgs) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let conf = get_configuration(None).await?;
+        let url = format!(
+            "http://{}{}/{}",
+            conf.leptos_options.site_addr,
+            app::TRAFFIC_PATH,
+            args.id
+        );
+        let response = reqwest::get(&url).await.wrap_err_with(|| {
+            format!("Could not reach the inspector at {url}, is sgrok running?")
+        })?;
+        if response.status() == StatusCode::NOT_FOUND {
+            bail!("There is no captured request {}", args.id);
+        }
+        let cycle: RequestCycle = response.error_for_status()?.json().await?;
+        println!("{}", args.format.render(&cycle));
+        Ok(())
+    })
+}
@@ -1,20 +1,25 @@
-use parking_lot::RwLock;
-use std::sync::Arc;
+use parking_lot::{Mutex, RwLock};
+use std::{convert::Infallible, sync::Arc};
 
-use app::App;
+use app::{breakpoints::Breakpoints, store::TrafficStore, App};
 use async_trait::async_trait;
 use axum::response::Response as AxumResponse;
 
 use axum::{
     body::Body,
-    extract::State,
-    http::{Request, Response, StatusCode, Uri},
-    response::IntoResponse,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{header, Request, Response, StatusCode, Uri},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     routing::get,
-    Router,
+    Json, Router,
 };
 use leptos::*;
-use shared_types::TrafficLog;
+use shared_types::{har::Har, RequestCycle, TunnelPorts};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
 
@@ -53,15 +58,93 @@ async fn get_static_file(uri: Uri, root: &str) -> Result<Response<Body>, (Status
     }
 }
 
+/// Push every request cycle the eaves proxies record to the browser as a server sent event
+fn live_traffic(
+    receiver: broadcast::Receiver<RequestCycle>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // A lagging receiver skips the cycles it missed, the UI can reload the full log for those
+    let events = BroadcastStream::new(receiver)
+        .filter_map(|cycle| cycle.ok())
+        .filter_map(|cycle| Event::default().json_data(cycle).ok())
+        .map(Ok);
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// A single captured request cycle by id, or the most recent one for `latest`
+fn request_cycle(
+    traffic_store: &RwLock<TrafficStore>,
+    id: &str,
+) -> Result<Json<RequestCycle>, StatusCode> {
+    let traffic_store = traffic_store.read();
+    let cycle = match id {
+        "latest" => traffic_store.iter().last(),
+        id => id.parse().ok().and_then(|id| traffic_store.get(id)),
+    };
+    cycle.cloned().map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// HAR files of long sessions easily outgrow the default body limit
+const MAX_HAR_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Clone)]
+struct HarState {
+    traffic_store: Arc<RwLock<TrafficStore>>,
+    live_traffic: broadcast::Sender<RequestCycle>,
+    default_ports: Option<TunnelPorts>,
+}
+
+async fn export_har(State(state): State<HarState>) -> impl IntoResponse {
+    let har = Har::from_cycles(state.traffic_store.read().iter());
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="sgrok.har""#,
+        )],
+        Json(har),
+    )
+}
+
+async fn import_har(
+    State(state): State<HarState>,
+    Json(har): Json<Har>,
+) -> Result<String, (StatusCode, String)> {
+    let cycles = har
+        .into_cycles(state.default_ports)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+    let total = cycles.len();
+    let mut imported = 0;
+    for mut cycle in cycles {
+        let mut traffic_store = state.traffic_store.write();
+        cycle.id = traffic_store.next_id();
+        if traffic_store.push(cycle.clone()) {
+            imported += 1;
+            let _ = state.live_traffic.send(cycle);
+        }
+    }
+    Ok(format!("Imported {imported} of {total} requests\n"))
+}
+
 pub struct UiServer {
     name: String,
-    traffic_log: Arc<RwLock<TrafficLog>>,
+    traffic_store: Arc<RwLock<TrafficStore>>,
+    live_traffic: broadcast::Sender<RequestCycle>,
+    breakpoints: Arc<Mutex<Breakpoints>>,
+    /// Ports of the open http tunnels, the first one is the default for imports
+    tunnel_ports: Arc<[TunnelPorts]>,
 }
 
-pub fn configure_ui_client(traffic_log: Arc<RwLock<TrafficLog>>) -> UiServer {
+pub fn configure_ui_client(
+    traffic_store: Arc<RwLock<TrafficStore>>,
+    live_traffic: broadcast::Sender<RequestCycle>,
+    breakpoints: Arc<Mutex<Breakpoints>>,
+    tunnel_ports: Arc<[TunnelPorts]>,
+) -> UiServer {
     UiServer {
         name: "uiserver".to_owned(),
-        traffic_log,
+        traffic_store,
+        live_traffic,
+        breakpoints,
+        tunnel_ports,
     }
 }
 
@@ -84,16 +167,43 @@ impl pingora::services::Service for UiServer {
         let routes = generate_route_list(App);
 
         // intermediate variable is neccesary to prevent self from moving into the closure in the context
-        let tl = self.traffic_log.clone();
+        let tl = self.traffic_store.clone();
+        let breakpoints = self.breakpoints.clone();
+        let tunnel_ports = self.tunnel_ports.clone();
+        let live = self.live_traffic.clone();
+        let cycles = self.traffic_store.clone();
+        let har_state = HarState {
+            traffic_store: self.traffic_store.clone(),
+            live_traffic: self.live_traffic.clone(),
+            // Imported traffic that does not say where it came from belongs to the first http tunnel
+            default_ports: self.tunnel_ports.first().cloned(),
+        };
+        let har_router = Router::new()
+            .route("/", get(export_har).post(import_har))
+            .layer(DefaultBodyLimit::max(MAX_HAR_SIZE))
+            .with_state(har_state);
         // build our application with a route
         let axum_app = Router::new()
             .leptos_routes_with_context(
                 &leptos_options,
                 routes,
-                move || provide_context(tl.clone()),
+                move || {
+                    provide_context(tl.clone());
+                    provide_context(breakpoints.clone());
+                    provide_context(tunnel_ports.clone());
+                },
                 App,
             )
             .route("/oida", get(|| async { "Hello, World!" }))
+            .route(
+                app::LIVE_TRAFFIC_PATH,
+                get(move || std::future::ready(live_traffic(live.subscribe()))),
+            )
+            .route(
+                &format!("{}/:id", app::TRAFFIC_PATH),
+                get(move |Path(id): Path<String>| std::future::ready(request_cycle(&cycles, &id))),
+            )
+            .nest(app::HAR_PATH, har_router)
             .fallback(file_and_error_handler)
             .with_state(leptos_options);
 
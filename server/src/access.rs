@@ -0,0 +1,306 @@
+use std::net::IpAddr;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Request, Response, StatusCode},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ipnet::IpNet;
+use shared_types::handshake::{AccessPolicy, BasicAuth, HandshakeError, LoginGate, Mode};
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+use crate::{session, settings, KeyMap};
+
+/// Cookie and query parameter a visitor can hand their login token over in
+const TOKEN_COOKIE: &str = "sgrok_token";
+
+/// The [`AccessPolicy`] of a tunnel, validated and ready to check visitors against.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    basic_auth: Option<BasicAuth>,
+    allowed_networks: Vec<IpNet>,
+    login: Option<LoginGate>,
+    /// Only send the login cookie over https, unless visitors reach the tunnel over plain http
+    secure_cookie: bool,
+}
+
+impl Access {
+    pub fn from_policy(
+        policy: &AccessPolicy,
+        mode: Mode,
+        secure_cookie: bool,
+    ) -> Result<Self, HandshakeError> {
+        if mode == Mode::Tcp && (policy.basic_auth.is_some() || policy.login.is_some()) {
+            return Err(HandshakeError::InvalidAccessPolicy(
+                "tcp tunnels can only be protected with an ip allowlist".into(),
+            ));
+        }
+        if let Some(BasicAuth { username, .. }) = &policy.basic_auth {
+            if username.is_empty() || username.contains(':') {
+                return Err(HandshakeError::InvalidAccessPolicy(
+                    "a basic auth username can not be empty or contain ':'".into(),
+                ));
+            }
+        }
+        let allowed_networks = policy
+            .allow_cidrs
+            .iter()
+            .map(|cidr| {
+                parse_network(cidr).ok_or_else(|| {
+                    HandshakeError::InvalidAccessPolicy(format!("'{cidr}' is not a valid CIDR"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Access {
+            basic_auth: policy.basic_auth.clone(),
+            allowed_networks,
+            login: policy.login.clone(),
+            secure_cookie,
+        })
+    }
+
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.allowed_networks.is_empty() || self.allowed_networks.iter().any(|n| n.contains(&ip))
+    }
+
+    /// Check a visitor of an http tunnel against the policy, returning the
+    /// response to send them instead when they are not let in. Credentials meant
+    /// for storm grok are stripped from the request so they never reach the
+    /// tunnel owner.
+    pub fn admit(
+        &self,
+        ip: IpAddr,
+        request: &mut Request<Body>,
+        key_map: &KeyMap,
+        auth: &settings::AuthRules,
+    ) -> Option<Response<Body>> {
+        if !self.allows_ip(ip) {
+            debug!("refusing {ip:?}, it is not on the allowlist");
+            return Some(deny(StatusCode::FORBIDDEN, None));
+        }
+        if let Some(expected) = &self.basic_auth {
+            if !basic_auth_matches(request, expected) {
+                let challenge = r#"Basic realm="storm grok", charset="UTF-8""#;
+                return Some(deny(StatusCode::UNAUTHORIZED, Some(challenge)));
+            }
+            request.headers_mut().remove(header::AUTHORIZATION);
+        }
+        if let Some(gate) = &self.login {
+            if let Some(token) = token_from_query(request) {
+                return Some(match login(&token, gate, key_map, auth) {
+                    true => hand_over_token(request, &token, self.secure_cookie),
+                    false => deny(StatusCode::FORBIDDEN, None),
+                });
+            }
+            let token = match (bearer_token(request), cookie_token(request)) {
+                (Some(token), _) | (None, Some(token)) => token,
+                (None, None) => {
+                    let challenge = r#"Bearer realm="storm grok""#;
+                    return Some(deny(StatusCode::UNAUTHORIZED, Some(challenge)));
+                }
+            };
+            if !login(&token, gate, key_map, auth) {
+                return Some(deny(StatusCode::FORBIDDEN, None));
+            }
+            strip_token(request);
+        }
+        None
+    }
+}
+
+/// Parse a network in CIDR notation, a bare address stands for just that address
+pub fn parse_network(cidr: &str) -> Option<IpNet> {
+    cidr.parse::<IpNet>()
+        .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+/// IPv4 addresses reach a dual stack listener as IPv6, match them as IPv4
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+fn deny(status: StatusCode, challenge: Option<&'static str>) -> Response<Body> {
+    let mut response = Response::builder().status(status);
+    if let Some(challenge) = challenge {
+        response = response.header(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+        .body(Body::from("You are not allowed to visit this tunnel\n"))
+        .unwrap()
+}
+
+fn login(token: &str, gate: &LoginGate, key_map: &KeyMap, auth: &settings::AuthRules) -> bool {
+    match session::authorize(token, key_map, auth) {
+        Ok(authorized) => {
+            gate.users.is_empty()
+                || authorized
+                    .user
+                    .is_some_and(|user| gate.users.contains(&user))
+        }
+        Err(e) => {
+            debug!("refusing login: {e:#}");
+            false
+        }
+    }
+}
+
+fn basic_auth_matches(request: &Request<Body>, expected: &BasicAuth) -> bool {
+    let encoded = match authorization(request).and_then(|v| v.strip_prefix("Basic ")) {
+        Some(encoded) => encoded,
+        None => return false,
+    };
+    let expected = format!("{}:{}", expected.username, expected.password);
+    match STANDARD.decode(encoded.trim()) {
+        // Constant time, so the time a refusal takes does not tell how much of a guess was right
+        Ok(decoded) => decoded.ct_eq(expected.as_bytes()).into(),
+        Err(_) => false,
+    }
+}
+
+fn authorization(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+}
+
+fn bearer_token(request: &Request<Body>) -> Option<String> {
+    authorization(request)
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+}
+
+fn cookies(request: &Request<Body>) -> impl Iterator<Item = (&str, &str)> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+}
+
+fn cookie_token(request: &Request<Body>) -> Option<String> {
+    cookies(request)
+        .find(|(name, _)| *name == TOKEN_COOKIE)
+        .map(|(_, token)| token.to_owned())
+}
+
+fn token_from_query(request: &Request<Body>) -> Option<String> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(TOKEN_COOKIE)?.strip_prefix('='))
+        .map(str::to_owned)
+}
+
+/// Put the token from the query in a cookie and send the visitor back to where
+/// they were going, so browsers only have to present it once.
+fn hand_over_token(request: &Request<Body>, token: &str, secure: bool) -> Response<Body> {
+    let query: Vec<&str> = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| pair.split('=').next() != Some(TOKEN_COOKIE))
+        .collect();
+    let location = match query.join("&") {
+        query if query.is_empty() => request.uri().path().to_owned(),
+        query => format!("{}?{}", request.uri().path(), query),
+    };
+    // Browsers would never send a `Secure` cookie back to a plain http tunnel
+    let secure = match secure {
+        true => "; Secure",
+        false => "",
+    };
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .header(
+            header::SET_COOKIE,
+            format!("{TOKEN_COOKIE}={token}; Path=/; HttpOnly{secure}; SameSite=Lax"),
+        )
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Remove the login token from the request, it should not reach the tunnel owner
+fn strip_token(request: &mut Request<Body>) {
+    if bearer_token(request).is_some() {
+        request.headers_mut().remove(header::AUTHORIZATION);
+    }
+    let remaining: Vec<String> = cookies(request)
+        .filter(|(name, _)| *name != TOKEN_COOKIE)
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    let headers = request.headers_mut();
+    headers.remove(header::COOKIE);
+    if !remaining.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&remaining.join("; ")) {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> AccessPolicy {
+        AccessPolicy {
+            basic_auth: None,
+            allow_cidrs: vec!["10.0.0.0/8".into(), "192.168.1.7".into()],
+            login: None,
+        }
+    }
+
+    #[test]
+    fn allowlist_matches_networks_and_addresses() {
+        let access = Access::from_policy(&policy(), Mode::Tcp, true).unwrap();
+        assert!(access.allows_ip("10.1.2.3".parse().unwrap()));
+        assert!(access.allows_ip("192.168.1.7".parse().unwrap()));
+        assert!(access.allows_ip("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!access.allows_ip("192.168.1.8".parse().unwrap()));
+        assert!(Access::default().allows_ip("192.168.1.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_policies_are_refused() {
+        let mut bad_cidr = policy();
+        bad_cidr.allow_cidrs.push("10.0.0.0/33".into());
+        assert!(Access::from_policy(&bad_cidr, Mode::Http, true).is_err());
+
+        let mut tcp_login = policy();
+        tcp_login.login = Some(LoginGate::default());
+        assert!(Access::from_policy(&tcp_login, Mode::Tcp, true).is_err());
+        assert!(Access::from_policy(&tcp_login, Mode::Http, true).is_ok());
+    }
+
+    #[test]
+    fn login_cookie_is_only_secure_over_https() {
+        let request = Request::builder()
+            .uri("/dashboard?sgrok_token=ey.token&tab=2")
+            .body(Body::empty())
+            .unwrap();
+        let cookie = |secure| {
+            let response = hand_over_token(&request, "ey.token", secure);
+            assert_eq!(response.headers()[header::LOCATION], "/dashboard?tab=2");
+            response.headers()[header::SET_COOKIE].clone()
+        };
+        assert_eq!(
+            cookie(true),
+            "sgrok_token=ey.token; Path=/; HttpOnly; Secure; SameSite=Lax"
+        );
+        assert_eq!(
+            cookie(false),
+            "sgrok_token=ey.token; Path=/; HttpOnly; SameSite=Lax"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Finding the address a visitor really connects from when the server sits
+//! behind proxies, like nginx in production.
+//!
+//! Only proxies in the configured trusted networks are believed. A visitor can
+//! put anything in `X-Forwarded-For`, but every trusted proxy appends the
+//! address it saw, so walking the chain from the right and stopping at the
+//! first hop that is not trusted gives an address nobody could forge.
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use axum::{body::Body, http::Request};
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::access::{canonical, parse_network};
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Networks of the proxies whose `X-Forwarded-For` and PROXY protocol headers are believed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TryFrom<Vec<String>> for TrustedProxies {
+    type Error = anyhow::Error;
+
+    fn try_from(cidrs: Vec<String>) -> Result<Self, Self::Error> {
+        cidrs
+            .iter()
+            .map(|cidr| {
+                parse_network(cidr)
+                    .ok_or_else(|| anyhow!("trusted proxy '{cidr}' is not a valid CIDR"))
+            })
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The visitor behind a connection from `peer`, given the `X-Forwarded-For`
+    /// values of its request in the order they were sent
+    pub fn client_ip<'a>(
+        &self,
+        peer: IpAddr,
+        forwarded_for: impl Iterator<Item = &'a str>,
+    ) -> IpAddr {
+        let mut client = canonical(peer);
+        if !self.contains(client) {
+            return client;
+        }
+        let hops: Vec<&str> = forwarded_for.flat_map(|value| value.split(',')).collect();
+        for hop in hops.iter().rev().map(|hop| hop.trim()) {
+            // Some proxies add the port, which says nothing about who the visitor is
+            let ip = hop
+                .parse::<IpAddr>()
+                .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()));
+            match ip {
+                Ok(ip) => client = canonical(ip),
+                // Whatever is further left can not be trusted to make sense either
+                Err(_) => break,
+            }
+            if !self.contains(client) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// The visitor behind a request that reached the server from `peer`
+    pub fn request_client_ip(&self, peer: IpAddr, request: &Request<Body>) -> IpAddr {
+        let forwarded_for = request
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        self.client_ip(peer, forwarded_for)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_hops() {
+        let trusted = TrustedProxies::try_from(vec!["::1".into(), "10.0.0.0/8".into()]).unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let client_ip = |peer: &str, forwarded_for: &[&str]| {
+            trusted.client_ip(ip(peer), forwarded_for.iter().copied())
+        };
+        // A visitor talking to the server directly can claim to be anyone
+        assert_eq!(client_ip("203.0.113.7", &["1.1.1.1"]), ip("203.0.113.7"));
+        // Nginx appended the visitor, what it sent itself is ignored
+        assert_eq!(
+            client_ip("::1", &["1.1.1.1, 203.0.113.7"]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip("::1", &["1.1.1.1", "203.0.113.7:51234, 10.0.0.5"]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(client_ip("::ffff:10.0.0.5", &["garbage"]), ip("10.0.0.5"));
+        assert_eq!(client_ip("::1", &[]), ip("::1"));
+        assert!(TrustedProxies::try_from(vec!["nginx".into()]).is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use jsonwebtoken::DecodingKey;
 use parking_lot::RwLock;
@@ -14,33 +18,68 @@ use tower::util::ServiceExt;
 use axum::{
     body::Body,
     extract::{ConnectInfo, Host},
-    http::{status::StatusCode, Request},
+    http::{header, status::StatusCode, Request},
     response::Response,
     routing::any,
     Extension, Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
 
+mod access;
+mod forwarded;
 mod jwt_key_store;
 mod server;
 mod session;
 mod settings;
+mod subdomain;
 
 type KeyMap = Arc<RwLock<HashMap<String, DecodingKey>>>;
-type ClientMap = Arc<RwLock<HashMap<Uuid, String>>>;
+type ClientMap = Arc<RwLock<HashMap<String, Route>>>;
+type ParkedMap = Arc<RwLock<HashMap<Uuid, session::ClientSession>>>;
 type HttpClient = hyper::client::Client<HttpConnector, Body>;
 type HttpsClient = hyper::client::Client<HttpsConnector<HttpConnector>, Body>;
 
+/// Where the traffic for a subdomain goes and who may send it
+#[derive(Clone, Debug)]
+struct Route {
+    target: String,
+    access: access::Access,
+    /// The client dropped off and nothing listens until it reconnects
+    parked: bool,
+}
+
+/// The subdomain a request was addressed to
+#[derive(Clone, Debug)]
+struct Subdomain(String);
+
+/// The visitor a request came from, looking past trusted proxies
+#[derive(Clone, Copy, Debug)]
+struct ClientIp(IpAddr);
+
 async fn forwarder(
     Extension(client): Extension<HttpClient>,
     Extension(client_map): Extension<ClientMap>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    host: Host,
-    req: Request<Body>,
+    Extension(key_map): Extension<KeyMap>,
+    Extension(auth): Extension<settings::AuthRules>,
+    Extension(Subdomain(subdomain)): Extension<Subdomain>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    mut req: Request<Body>,
 ) -> Response<Body> {
-    let uuid = resolve_uuid_from_host(&host.0).unwrap();
-    let target = match client_map.read().get(&uuid) {
-        Some(target) => format!("http://{}", target),
+    let route = client_map.read().get(&subdomain).cloned();
+    let target = match route {
+        Some(route) if route.parked => {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::RETRY_AFTER, "5")
+                .body(Body::from("Client is reconnecting, try again shortly\n"))
+                .unwrap();
+        }
+        Some(route) => {
+            if let Some(denied) = route.access.admit(client_ip, &mut req, &key_map, &auth) {
+                return denied;
+            }
+            format!("http://{}", route.target)
+        }
         None => {
             return Response::builder()
                 .status(StatusCode::NOT_FOUND)
@@ -48,7 +87,9 @@ async fn forwarder(
                 .unwrap();
         }
     };
-    match hyper_reverse_proxy::call(addr.ip(), &target, req, &client).await {
+    // The proxy appends to what is there, the tunnel should only see the visitor found above
+    req.headers_mut().remove(forwarded::X_FORWARDED_FOR);
+    match hyper_reverse_proxy::call(client_ip, &target, req, &client).await {
         Ok(response) => response,
         Err(_error) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -62,9 +103,15 @@ async fn handler(Extension(client_map): Extension<ClientMap>, host: Host) -> &'s
     "This sentence is false.\n"
 }
 
-fn resolve_uuid_from_host(host: &str) -> Option<Uuid> {
-    let client_id = host.split('.').next()?;
-    Uuid::parse_str(client_id).ok()
+/// Find the subdomain of `domain` a host header points at. Only a single label
+/// directly under `domain` counts as a subdomain.
+fn resolve_subdomain_from_host(host: &str, domain: &str) -> Option<String> {
+    let host = host.split(':').next()?;
+    let label = host.strip_suffix(domain)?.strip_suffix('.')?;
+    match label.is_empty() || label.contains('.') {
+        true => None,
+        false => Some(label.to_ascii_lowercase()),
+    }
 }
 
 #[tokio::main]
@@ -72,7 +119,9 @@ async fn main() {
     let config = settings::Settings::new();
     let key_store: KeyMap = Arc::new(RwLock::new(HashMap::new()));
     let client_map: ClientMap = Arc::new(RwLock::new(HashMap::new()));
-    let sg_server = server::start_storm_grok_server(&config, client_map.clone(), key_store.clone());
+    let parked_map: ParkedMap = Arc::new(RwLock::new(HashMap::new()));
+    let sg_server =
+        server::start_storm_grok_server(&config, client_map.clone(), parked_map, key_store.clone());
 
     let http_client: HttpClient = hyper::Client::new();
 
@@ -86,16 +135,27 @@ async fn main() {
     let forwarder_router = Router::new().fallback(any(forwarder));
     let default_router = Router::new().fallback(any(handler));
 
+    let domain = config.server.domain.clone();
+    let trusted_proxies = config.server.trusted_proxies.clone();
     let app = Router::new()
         .fallback(any(
-            |Host(hostname): Host, request: Request<Body>| async move {
-                match resolve_uuid_from_host(hostname.as_str()) {
-                    Some(_) => forwarder_router.oneshot(request).await,
+            |Host(hostname): Host,
+             ConnectInfo(addr): ConnectInfo<SocketAddr>,
+             mut request: Request<Body>| async move {
+                match resolve_subdomain_from_host(hostname.as_str(), &domain) {
+                    Some(subdomain) => {
+                        let client_ip = trusted_proxies.request_client_ip(addr.ip(), &request);
+                        request.extensions_mut().insert(Subdomain(subdomain));
+                        request.extensions_mut().insert(ClientIp(client_ip));
+                        forwarder_router.oneshot(request).await
+                    }
                     None => default_router.oneshot(request).await,
                 }
             },
         ))
         .layer(Extension(client_map))
+        .layer(Extension(key_store.clone()))
+        .layer(Extension(config.auth.clone()))
         .layer(Extension(http_client));
 
     let addr = format!("{}:{}", config.server.http_host, config.server.http_port);
@@ -140,6 +200,18 @@ async fn main() {
 
 #[cfg(test)]
 mod tests {
+    use super::resolve_subdomain_from_host;
+
+    #[test]
+    fn subdomain_is_resolved_from_host() {
+        let resolve = |host| resolve_subdomain_from_host(host, "stormgrok.nl");
+        assert_eq!(resolve("my-pr-123.stormgrok.nl"), Some("my-pr-123".into()));
+        assert_eq!(resolve("My-PR.stormgrok.nl:443"), Some("my-pr".into()));
+        assert_eq!(resolve("stormgrok.nl"), None);
+        assert_eq!(resolve("a.b.stormgrok.nl"), None);
+        assert_eq!(resolve("evilstormgrok.nl"), None);
+    }
+
     #[test]
     #[should_panic]
     fn another() {
@@ -4,7 +4,7 @@ use std::net::SocketAddr;
 use tokio::task::JoinHandle;
 use tracing::info;
 
-use crate::{session, settings, ClientMap, KeyMap};
+use crate::{session, settings, ClientMap, KeyMap, ParkedMap};
 
 #[derive(Debug)]
 pub struct ChildTask<T> {
@@ -20,6 +20,7 @@ impl<T> Drop for ChildTask<T> {
 pub async fn start_storm_grok_server(
     config: &settings::Settings,
     client_map: ClientMap,
+    parked_map: ParkedMap,
     key_map: KeyMap,
 ) -> Result<()> {
     let server_address = format!("{}:{:?}", config.server.quic_host, config.server.quic_port);
@@ -30,7 +31,14 @@ pub async fn start_storm_grok_server(
 
     info!("Starting Quic server on {:?}", server_address);
     let endpoint = Endpoint::server(server_config, server_address)?;
-    handle_conns_loop(endpoint.clone(), client_map, key_map, config.auth.clone()).await;
+    handle_conns_loop(
+        endpoint.clone(),
+        client_map,
+        parked_map,
+        key_map,
+        config.clone(),
+    )
+    .await;
     info!("Waiting for clean quic server shutdown");
     endpoint.wait_idle().await;
     Ok(())
@@ -42,14 +50,21 @@ pub async fn start_storm_grok_server(
 async fn handle_conns_loop(
     endpoint: Endpoint,
     client_map: ClientMap,
+    parked_map: ParkedMap,
     key_map: KeyMap,
-    auth: settings::AuthRules,
+    config: settings::Settings,
 ) {
     // TODO: I guess this vector will grow very long now.. Need something to prune the done tasks off.
     // Same situation applies to client by the way!!
     let mut handles = Vec::new();
     while let Some(conn) = endpoint.accept().await {
-        let ses = session::start_session(conn, client_map.clone(), key_map.clone(), auth.clone());
+        let ses = session::start_session(
+            conn,
+            client_map.clone(),
+            parked_map.clone(),
+            key_map.clone(),
+            config.clone(),
+        );
         handles.push(ChildTask {
             inner: tokio::spawn(ses),
         });
@@ -1,32 +1,31 @@
 use quinn::{Connecting, Connection};
-use tokio::net::TcpListener;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
 use serde::{Deserialize, Serialize};
-use std::io::ErrorKind;
+use shared_types::{
+    handshake::{
+        self, Accepted, Address, Capability, ClientHello, FrameError, HandshakeError, Mode,
+        ServerHello, TunnelAssignment, TunnelId, TunnelRequest,
+    },
+    proxy_protocol::{self, Addresses, Decoded, Version},
+};
+use std::{io::ErrorKind, net::SocketAddr};
 use tokio::time::{self as time, Duration};
 use tracing::log::{debug, error, info};
 use uuid::Uuid;
 
-use crate::{settings, ClientMap, KeyMap};
+use crate::{access::Access, settings, subdomain, ClientMap, KeyMap, ParkedMap, Route};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum Mode {
-    Http,
-    Tcp,
-}
-
-impl From<u8> for Mode {
-    fn from(num: u8) -> Self {
-        match num {
-            b't' => Mode::Tcp,
-            _ => Mode::Http,  // default to Http
-        }
-    }
-}
+const CAPABILITIES: &[Capability] = &[Capability::Resume, Capability::PeerAddress];
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(4);
+/// How long a trusted proxy gets to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
 
 async fn send_ping(connection: Connection) -> Result<()> {
     let mut interval = time::interval(HEARTBEAT_INTERVAL);
@@ -42,35 +41,166 @@ async fn send_ping(connection: Connection) -> Result<()> {
 pub struct RegisteredListener {
     tcp_listener: TcpListener,
     client_map: ClientMap,
-    id: Uuid,
+    subdomain: String,
+    /// The client asked for the subdomain, a resumed client has to ask for it again
+    requested: bool,
+    mode: Mode,
+    access: Access,
+    /// Start every stream with the visitor's address, only done for tcp tunnels
+    peer_address: bool,
+}
+
+impl RegisteredListener {
+    fn address(&self) -> Result<Address> {
+        Ok(match self.mode {
+            Mode::Tcp => Address::Tcp {
+                port: self.tcp_listener.local_addr()?.port(),
+            },
+            Mode::Http => Address::Http {
+                subdomain: self.subdomain.clone(),
+            },
+        })
+    }
+
+    /// Swap in the access policy a resumed client asked for this time
+    fn set_access(&mut self, access: Access) {
+        if let Some(route) = self.client_map.write().get_mut(&self.subdomain) {
+            route.access = access.clone();
+        }
+        self.access = access;
+    }
+
+    /// Let the forwarder know whether anything is listening behind the route
+    fn set_parked(&self, parked: bool) {
+        if let Some(route) = self.client_map.write().get_mut(&self.subdomain) {
+            route.parked = parked;
+        }
+    }
+}
+
+/// The listeners of an admitted client and who it authenticated as. Parked
+/// sessions are only handed back to the same user.
+#[derive(Debug)]
+pub struct ClientSession {
+    user: Option<String>,
+    listeners: Vec<RegisteredListener>,
 }
 
 impl Drop for RegisteredListener {
     fn drop(&mut self) {
-        info!("de-registering {:?}", &self.id);
-        self.client_map.write().remove(&self.id);
+        info!("de-registering {:?}", &self.subdomain);
+        self.client_map.write().remove(&self.subdomain);
+    }
+}
+
+/// Form a bridge between a tcp socket and a quic connection tx/rx <-> rx/tx. Every
+/// stream is prefixed with the tunnel id so the client knows where it should go,
+/// and with the visitor's address when the tunnel asked for it.
+async fn connect_tcp_to_bi_quic(
+    listener: &RegisteredListener,
+    tunnel_id: TunnelId,
+    conn: Connection,
+    server: &settings::Server,
+) {
+    while let Ok((client, addr)) = listener.tcp_listener.accept().await {
+        let tcp = listener.mode == Mode::Tcp;
+        let proxy_header =
+            tcp && server.tcp_proxy_protocol && server.trusted_proxies.contains(addr.ip());
+        // Http listeners only ever see the forwarder, which checks access itself
+        let access = tcp.then(|| listener.access.clone());
+        let peer_address = tcp && listener.peer_address;
+        tokio::spawn(bridge_connection(
+            client,
+            addr,
+            tunnel_id,
+            conn.clone(),
+            proxy_header,
+            access,
+            peer_address,
+        ));
     }
 }
 
-/// Form a bridge between a tcp socket and a quic connection tx/rx <-> rx/tx
-async fn connect_tcp_to_bi_quic(listener: RegisteredListener, conn: Connection) {
-    while let Ok((mut client, addr)) = listener.tcp_listener.accept().await {
-        debug!("Created tcp listen port on {:?}", addr);
-        let (mut server_send, mut server_recv) = match conn.open_bi().await {
-            Ok(res) => res,
-            Err(e) => {
-                error!("Could not establish bi quic conn for forwarding: {e:?}");
+/// Bridge a single visitor's connection. This runs on a task of its own, so a
+/// visitor that is slow to get going does not hold up the others.
+async fn bridge_connection(
+    mut client: TcpStream,
+    addr: SocketAddr,
+    tunnel_id: TunnelId,
+    conn: Connection,
+    proxy_header: bool,
+    access: Option<Access>,
+    peer_address: bool,
+) {
+    let mut addresses = Addresses {
+        source: addr,
+        destination: client.local_addr().unwrap_or(addr),
+    };
+    // Bytes the load balancer sent right behind its header
+    let mut early_data = vec![];
+    if proxy_header {
+        match time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut client)).await {
+            Ok(Ok((proxied, rest))) => {
+                addresses = proxied.unwrap_or(addresses);
+                early_data = rest;
+            }
+            Ok(Err(e)) => {
+                debug!("Refusing {:?}: {:#}", addr, e);
                 return;
             }
-        };
-        debug!("Made new biquic {:?}", server_send);
-        tokio::spawn(async move {
-            let (mut client_recv, mut client_send) = client.split();
-            tokio::select! {
-                _ = tokio::io::copy(&mut server_recv, &mut client_send) => {}
-                _ = tokio::io::copy(&mut client_recv, &mut server_send) => {}
-            };
-        });
+            Err(_) => {
+                debug!(
+                    "Refusing {:?}, it sent no PROXY protocol header in time",
+                    addr
+                );
+                return;
+            }
+        }
+    }
+    if access.is_some_and(|access| !access.allows_ip(addresses.source.ip())) {
+        debug!(
+            "Refusing {:?}, it is not on the allowlist",
+            addresses.source
+        );
+        return;
+    }
+    debug!("Created tcp listen port on {:?}", addresses.source);
+    let (mut server_send, mut server_recv) = match conn.open_bi().await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Could not establish bi quic conn for forwarding: {e:?}");
+            return;
+        }
+    };
+    debug!("Made new biquic {:?}", server_send);
+    let mut prefix = tunnel_id.to_be_bytes().to_vec();
+    if peer_address {
+        prefix.extend(addresses.encode(Version::V2));
+    }
+    prefix.extend(early_data);
+    if let Err(e) = server_send.write_all(&prefix).await {
+        error!("Could not send tunnel id over bi quic conn: {e:?}");
+        return;
+    }
+    let (mut client_recv, mut client_send) = client.split();
+    tokio::select! {
+        _ = tokio::io::copy(&mut server_recv, &mut client_send) => {}
+        _ = tokio::io::copy(&mut client_recv, &mut server_send) => {}
+    };
+}
+
+/// Read the PROXY protocol header a trusted proxy starts a connection with,
+/// returning the addresses in it and whatever was read past it.
+async fn read_proxy_header(client: &mut TcpStream) -> Result<(Option<Addresses>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(proxy_protocol::V2_FIXED_LEN);
+    loop {
+        if client.read_buf(&mut buf).await? == 0 {
+            bail!("the connection closed before the PROXY protocol header was complete");
+        }
+        match proxy_protocol::decode(&buf)? {
+            Decoded::Incomplete => {}
+            Decoded::Header { addresses, len } => return Ok((addresses, buf.split_off(len))),
+        }
     }
 }
 
@@ -103,14 +233,15 @@ async fn start_local_tcp_server(mode: Mode) -> Result<TcpListener> {
 }
 
 /// Accept an incoming quic connection, negotiate a 'permanent' bidirectional
-/// pipe with the connecting client. Assign them an address and tell them about
-/// it. Wire up the assigned tcp connection to their pipe and start a pinging
-/// routine to ensure the pipe remains open.
+/// pipe with the connecting client. Assign every tunnel they ask for an address
+/// and tell them about it. Wire up the assigned tcp connections to their pipe and
+/// start a pinging routine to ensure the pipe remains open.
 pub async fn start_session(
     conn: Connecting,
     client_map: ClientMap,
+    parked_map: ParkedMap,
     key_map: KeyMap,
-    auth: settings::AuthRules,
+    config: settings::Settings,
 ) {
     info!("Establishing incoming connection");
     let conn: Connection = match conn.await {
@@ -120,7 +251,15 @@ pub async fn start_session(
             return;
         }
     };
-    let listener = match connect_client(conn.clone(), key_map, client_map, &auth).await {
+    let (session, resume_token) = match connect_client(
+        conn.clone(),
+        key_map,
+        client_map,
+        parked_map.clone(),
+        &config,
+    )
+    .await
+    {
         Ok(res) => res,
         Err(e) => {
             error!("Encountered '{:#}' while handshaking client", e);
@@ -128,85 +267,336 @@ pub async fn start_session(
             return;
         }
     };
+    let bridges =
+        futures::future::join_all(session.listeners.iter().enumerate().map(|(id, listener)| {
+            connect_tcp_to_bi_quic(listener, id as TunnelId, conn.clone(), &config.server)
+        }));
     tokio::select!(
-        _ = connect_tcp_to_bi_quic(listener, conn.clone()) => {},
+        _ = bridges => {},
         _ = send_ping(conn) => {},
     );
+    let grace_period = Duration::from_secs(config.server.resume_grace_period);
+    park_listeners(session, resume_token, parked_map, grace_period);
+}
+
+/// Keep the listeners of a client that dropped off around for a grace period, so
+/// the client can reconnect with its resume token and reclaim its addresses. Once
+/// the grace period expires the listeners are dropped, which de-registers them.
+fn park_listeners(
+    session: ClientSession,
+    resume_token: Uuid,
+    parked_map: ParkedMap,
+    grace_period: Duration,
+) {
+    info!(
+        "parking {:?} for {:?} awaiting reconnect",
+        subdomains(&session.listeners),
+        grace_period
+    );
+    for listener in &session.listeners {
+        listener.set_parked(true);
+    }
+    parked_map.write().insert(resume_token, session);
+    tokio::spawn(async move {
+        time::sleep(grace_period).await;
+        if parked_map.write().remove(&resume_token).is_some() {
+            info!("grace period expired for resume token {resume_token:?}");
+        }
+    });
+}
+
+/// Take parked listeners back out of the parked map. They are only handed out
+/// again to the same user asking for tunnels with the same modes and requested
+/// subdomains in the same order, a resume token alone is not enough.
+fn reclaim_listeners(
+    parked_map: &ParkedMap,
+    resume_token: Option<Uuid>,
+    user: Option<&str>,
+    tunnels: &[TunnelRequest],
+) -> Option<Vec<RegisteredListener>> {
+    let resume_token = resume_token?;
+    let mut parked = parked_map.write();
+    let session = parked.get(&resume_token)?;
+    let same_tunnels = session.listeners.len() == tunnels.len()
+        && session
+            .listeners
+            .iter()
+            .zip(tunnels)
+            .all(|(listener, tunnel)| {
+                listener.mode == tunnel.mode
+                    && listener.requested.then_some(listener.subdomain.as_str())
+                        == tunnel.subdomain.as_deref()
+            });
+    if session.user.as_deref() != user || !same_tunnels {
+        return None;
+    }
+    let session = parked.remove(&resume_token)?;
+    for listener in &session.listeners {
+        listener.set_parked(false);
+    }
+    Some(session.listeners)
+}
+
+fn subdomains(listeners: &[RegisteredListener]) -> Vec<&str> {
+    listeners.iter().map(|l| l.subdomain.as_str()).collect()
 }
 
 /// Connects a client
 ///
 /// The basic contract is that a client connects to this server and immediately
-/// opens a single bidirectional connection. This server accepts that connection
-/// and the client sends a token over the connection. The token is validated
-/// here according to the rules set in the 'auth' block in config.
+/// opens a single bidirectional connection. The client sends a [`ClientHello`]
+/// over it, containing a token that is validated here according to the rules
+/// set in the 'auth' block in config.
 ///
-/// If the token is succesfully validated this server sends an address back to
-/// the client and after that the client should start listening for bidirectional
-/// connections.
+/// The server always answers with a [`ServerHello`]. If the client was admitted
+/// it contains the public addresses of its tunnels and a fresh resume token, and
+/// after that the client should start listening for bidirectional connections.
+/// When a client reconnects with the resume token of a parked session it gets
+/// its old addresses back.
 async fn connect_client(
     conn: Connection,
     key_map: KeyMap,
     client_map: ClientMap,
-    auth: &settings::AuthRules,
-) -> Result<RegisteredListener> {
+    parked_map: ParkedMap,
+    config: &settings::Settings,
+) -> Result<(ClientSession, Uuid)> {
     let (mut send, mut recv) = conn.accept_bi().await?;
-    // Since JWT's have to fit in a header 8kb is the practical upper limit on token size
-    let received_bytes = recv.read_to_end(8192).await?;
-    let requested_mode = Mode::from(received_bytes[0]);
-
-    let tcp_listener = start_local_tcp_server(requested_mode).await?;
-    let tcp_addr = tcp_listener.local_addr()?;
-    let mut id = Uuid::new_v4();
-
-    if auth.enabled {
-        let token = String::from_utf8_lossy(&received_bytes[1..]);
-        let kid = decode_header(&token)?
-            .kid
-            .ok_or_else(|| anyhow!("No kid found in token header"))?;
-
-        let token_message = match key_map.read().get(&kid) {
-            Some(dec_key) => decode::<Claims>(&token, dec_key, &Validation::new(Algorithm::RS256))
-                .context("Failed to decode token")?,
-            None => bail!("No valid DecodingKey found for 'kid={kid}'"), // todo: try fetching new keys before bailing
-        };
+    let received_bytes = recv.read_to_end(handshake::MAX_FRAME_LEN).await?;
 
-        match validate_claims(token_message.claims, auth) {
-            Err(e) => {
-                send.reset(1u32.into())?;
-                return Err(e);
-            }
-            Ok(Some(exact_id)) => id = Uuid::parse_str(&exact_id).unwrap(),
-            _ => (),
-        }
+    let (version, outcome) = match handshake::decode::<ClientHello>(&received_bytes) {
+        Ok((version, hello)) => (
+            version,
+            admit_client(hello, key_map, client_map, parked_map, config).await,
+        ),
+        // An answer in a frame would only confuse an unframed client, the reason the
+        // connection is closed with tells it what is going on in plain text
+        Err(FrameError::BadMagic) => bail!(HandshakeError::UnsupportedVersion {
+            requested: handshake::UNFRAMED_PROTOCOL_VERSION,
+            min: handshake::MIN_PROTOCOL_VERSION,
+            max: handshake::PROTOCOL_VERSION,
+        }),
+        Err(FrameError::UnsupportedVersion(requested)) => (
+            handshake::PROTOCOL_VERSION,
+            Err(HandshakeError::UnsupportedVersion {
+                requested,
+                min: handshake::MIN_PROTOCOL_VERSION,
+                max: handshake::PROTOCOL_VERSION,
+            }),
+        ),
+        Err(e) => (
+            handshake::PROTOCOL_VERSION,
+            Err(HandshakeError::Malformed(e.to_string())),
+        ),
+    };
+
+    let server_hello = ServerHello {
+        capabilities: CAPABILITIES.to_vec(),
+        result: outcome
+            .as_ref()
+            .map(|(_, accepted)| accepted.clone())
+            .map_err(HandshakeError::clone),
+    };
+    send.write_all(&handshake::encode(version, &server_hello))
+        .await?;
+    send.finish().await?;
+
+    let (session, accepted) = outcome?;
+    info!(
+        "Succesfully connected new quic client with {:?}",
+        subdomains(&session.listeners)
+    );
+    Ok((session, accepted.resume_token))
+}
+
+/// Decide whether a client may open its tunnels and if so, hand it a listener per tunnel.
+async fn admit_client(
+    hello: ClientHello,
+    key_map: KeyMap,
+    client_map: ClientMap,
+    parked_map: ParkedMap,
+    config: &settings::Settings,
+) -> Result<(ClientSession, Accepted), HandshakeError> {
+    info!(
+        "Handshaking client '{} {}' running on {} for {} tunnel(s)",
+        hello.client.name,
+        hello.client.version,
+        hello.client.os,
+        hello.tunnels.len()
+    );
+    let max_tunnels = config.server.max_tunnels;
+    if hello.tunnels.is_empty() || hello.tunnels.len() > max_tunnels {
+        return Err(HandshakeError::Malformed(format!(
+            "a client has to ask for 1 to {max_tunnels} tunnels"
+        )));
     }
-    {
-        // Check if the UUID (id) exists in client_map. UUID conflicts are normally near impossible
-        // but can occur when UUIDs are manually assigned. If a conflict is found, a new UUID is
-        // generated and used instead, ensuring uniqueness. No errors are thrown for conflicts.
-        let mut writable_client_map = client_map.write();
-        if writable_client_map.contains_key(&id) {
-            id = Uuid::new_v4();
+    let internal = |e: anyhow::Error| HandshakeError::Internal(format!("{:#}", e));
+    let authorized = match config.auth.enabled {
+        true => authorize(&hello.token, &key_map, &config.auth)
+            .map_err(|e| HandshakeError::Unauthorized(format!("{:#}", e)))?,
+        false => Authorized::default(),
+    };
+    let secure_cookie = config.server.serves_https();
+    let accesses = hello
+        .tunnels
+        .iter()
+        .map(|t| Access::from_policy(&t.access, t.mode, secure_cookie))
+        .collect::<Result<Vec<_>, _>>()?;
+    let user = authorized.user.clone();
+    if let Some(mut listeners) = reclaim_listeners(
+        &parked_map,
+        hello.options.resume_token,
+        user.as_deref(),
+        &hello.tunnels,
+    ) {
+        info!("Resumed parked session for {:?}", subdomains(&listeners));
+        for ((listener, access), tunnel) in listeners.iter_mut().zip(accesses).zip(&hello.tunnels) {
+            listener.set_access(access);
+            listener.peer_address = tunnel.peer_address;
         }
-        writable_client_map.insert(id, String::new());
+        return accept(ClientSession { user, listeners }, config).map_err(internal);
     }
-    info!("Succesfully connected new quic client with {id:?}");
-    match requested_mode {
-        Mode::Tcp => send.write_all(&tcp_addr.port().to_be_bytes()).await?,
-        Mode::Http => send.write_all(id.as_bytes()).await?,
+
+    // Only the first tunnel without a requested subdomain gets the id the issuer dictates
+    let mut exact_id = authorized.exact_id;
+    let mut listeners = Vec::with_capacity(hello.tunnels.len());
+    for (tunnel, access) in hello.tunnels.into_iter().zip(accesses) {
+        let listener = match tunnel.subdomain {
+            Some(_) if tunnel.mode == Mode::Tcp => {
+                return Err(HandshakeError::InvalidSubdomain(
+                    "subdomains can only be requested for http tunnels".into(),
+                ))
+            }
+            Some(requested) => {
+                subdomain::check(&requested, authorized.user.as_deref(), &config.subdomains)?;
+                register_listener(
+                    tunnel.mode,
+                    requested,
+                    true,
+                    access,
+                    tunnel.peer_address,
+                    client_map.clone(),
+                )
+                .await?
+            }
+            None => {
+                let name = exact_id
+                    .take()
+                    .map(|id| id.to_ascii_lowercase())
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                register_listener(
+                    tunnel.mode,
+                    name,
+                    false,
+                    access,
+                    tunnel.peer_address,
+                    client_map.clone(),
+                )
+                .await?
+            }
+        };
+        listeners.push(listener);
     }
-    send.finish().await?;
+    accept(ClientSession { user, listeners }, config).map_err(internal)
+}
 
+fn accept(
+    session: ClientSession,
+    config: &settings::Settings,
+) -> Result<(ClientSession, Accepted)> {
+    let tunnels = session
+        .listeners
+        .iter()
+        .enumerate()
+        .map(|(id, listener)| {
+            let address = listener.address()?;
+            Ok(TunnelAssignment {
+                id: id as TunnelId,
+                public_url: config.server.public_url(&address),
+                address,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let accepted = Accepted {
+        tunnels,
+        resume_token: Uuid::new_v4(),
+    };
+    Ok((session, accepted))
+}
+
+/// Who a validated token belongs to.
+#[derive(Debug, Default)]
+pub struct Authorized {
+    /// Verified email address or subject of the token
+    pub user: Option<String>,
+    /// Subdomain the issuer of the token dictates
+    exact_id: Option<String>,
+}
+
+/// Validate a JWT according to the auth rules.
+pub fn authorize(token: &str, key_map: &KeyMap, auth: &settings::AuthRules) -> Result<Authorized> {
+    let kid = decode_header(token)?
+        .kid
+        .ok_or_else(|| anyhow!("No kid found in token header"))?;
+
+    let token_message = match key_map.read().get(&kid) {
+        Some(dec_key) => decode::<Claims>(token, dec_key, &Validation::new(Algorithm::RS256))
+            .context("Failed to decode token")?,
+        None => bail!("No valid DecodingKey found for 'kid={kid}'"), // todo: try fetching new keys before bailing
+    };
+    let claims = token_message.claims;
+    let user = match (claims.email_verified, &claims.email) {
+        (Some(true), Some(email)) => Some(email.clone()),
+        _ => claims.sub.clone(),
+    };
+    let exact_id = validate_claims(claims, auth)?;
+    Ok(Authorized { user, exact_id })
+}
+
+/// Bind a fresh tcp listener for a client and register it under `subdomain`.
+///
+/// Conflicts are normally near impossible for randomly assigned subdomains but
+/// can occur when they are manually assigned. When the client explicitly asked
+/// for `subdomain` a conflict is an error, otherwise a fresh random subdomain is
+/// used instead.
+async fn register_listener(
+    mode: Mode,
+    mut subdomain: String,
+    requested: bool,
+    access: Access,
+    peer_address: bool,
+    client_map: ClientMap,
+) -> Result<RegisteredListener, HandshakeError> {
+    let tcp_listener = start_local_tcp_server(mode)
+        .await
+        .map_err(|e| HandshakeError::Internal(format!("{:#}", e)))?;
     let tcp_addr = tcp_listener.local_addr().unwrap();
-    debug!(
-        "Setting up client session with tcp listener on {:?}",
-        tcp_addr
-    );
-    client_map.write().insert(id, tcp_addr.to_string());
+    {
+        let mut writable_client_map = client_map.write();
+        if writable_client_map.contains_key(&subdomain) {
+            if requested {
+                return Err(HandshakeError::SubdomainUnavailable(subdomain));
+            }
+            subdomain = Uuid::new_v4().to_string();
+        }
+        debug!(
+            "Setting up client session with tcp listener on {:?}",
+            tcp_addr
+        );
+        let route = Route {
+            target: tcp_addr.to_string(),
+            access: access.clone(),
+            parked: false,
+        };
+        writable_client_map.insert(subdomain.clone(), route);
+    }
     Ok(RegisteredListener {
         tcp_listener,
         client_map,
-        id,
+        subdomain,
+        requested,
+        mode,
+        access,
+        peer_address,
     })
 }
 
@@ -240,3 +630,54 @@ fn validate_claims(claims: Claims, auth: &settings::AuthRules) -> Result<Option<
     }
     bail!("This token is not authorized!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel(subdomain: Option<&str>) -> TunnelRequest {
+        TunnelRequest {
+            mode: Mode::Http,
+            subdomain: subdomain.map(str::to_owned),
+            access: Default::default(),
+            peer_address: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn parked_listeners_go_back_to_the_same_user_and_subdomains() {
+        let client_map = ClientMap::default();
+        let parked_map = ParkedMap::default();
+        let resume_token = Uuid::new_v4();
+        let register = |subdomain: &str, requested| {
+            register_listener(
+                Mode::Http,
+                subdomain.into(),
+                requested,
+                Access::default(),
+                false,
+                client_map.clone(),
+            )
+        };
+        let listeners = vec![
+            register("my-pr-123", true).await.unwrap(),
+            register("0b7c", false).await.unwrap(),
+        ];
+        let user = Some("you@example.com".to_string());
+        parked_map
+            .write()
+            .insert(resume_token, ClientSession { user, listeners });
+
+        let asked = [tunnel(Some("my-pr-123")), tunnel(None)];
+        let reclaim = |user, tunnels: &[TunnelRequest]| {
+            reclaim_listeners(&parked_map, Some(resume_token), user, tunnels)
+        };
+        assert!(reclaim(Some("someone@else.com"), &asked).is_none());
+        assert!(reclaim(None, &asked).is_none());
+        assert!(reclaim(Some("you@example.com"), &[tunnel(None), tunnel(None)]).is_none());
+        assert!(reclaim(Some("you@example.com"), &asked[..1]).is_none());
+        let reclaimed = reclaim(Some("you@example.com"), &asked).unwrap();
+        assert_eq!(subdomains(&reclaimed), ["my-pr-123", "0b7c"]);
+        assert!(parked_map.read().is_empty());
+    }
+}
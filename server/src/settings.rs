@@ -2,9 +2,12 @@ use config::{Config, Environment, File};
 use rustls::{Certificate, PrivateKey};
 
 use serde::Deserialize;
-use std::{fs, io::BufReader, path::PathBuf};
+use shared_types::handshake::Address;
+use std::{collections::HashMap, fs, io::BufReader, path::PathBuf};
 use tracing_subscriber::{fmt, EnvFilter};
 
+use crate::forwarded::TrustedProxies;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Log {
     pub level: String,
@@ -23,9 +26,40 @@ pub struct Server {
     pub quic_host: String,
     pub http_port: u16,
     pub quic_port: u16,
+    /// Seconds a dropped client may take to reconnect and reclaim its address
+    pub resume_grace_period: u64,
+    /// How many tunnels a single client connection may open
+    pub max_tunnels: usize,
+    /// Domain under which http tunnels get their subdomain
+    pub domain: String,
+    /// Url of http tunnels as seen from the internet, `{subdomain}` is filled in per tunnel
+    pub public_http_url: String,
+    /// Host at which tcp tunnels are reachable from the internet
+    pub public_tcp_host: String,
+    /// Networks of the proxies in front of the server, like nginx, whose
+    /// `X-Forwarded-For` and PROXY protocol headers are believed
+    #[serde(default)]
+    pub trusted_proxies: TrustedProxies,
+    /// Trusted proxies start every connection to a tcp tunnel with a PROXY protocol header
+    #[serde(default)]
+    pub tcp_proxy_protocol: bool,
     pub tls: Option<Tls>,
 }
 
+impl Server {
+    /// Visitors reach http tunnels over https, as opposed to plain http in development
+    pub fn serves_https(&self) -> bool {
+        self.public_http_url.starts_with("https://")
+    }
+
+    pub fn public_url(&self, address: &Address) -> String {
+        match address {
+            Address::Http { subdomain } => self.public_http_url.replace("{subdomain}", subdomain),
+            Address::Tcp { port } => format!("tcp://{}:{}", self.public_tcp_host, port),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AuthRules {
     pub jwt_key_endpoints: Vec<String>,
@@ -35,6 +69,16 @@ pub struct AuthRules {
     pub host_domains: Vec<String>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct SubdomainRules {
+    /// Subdomains nobody can claim
+    #[serde(default)]
+    pub blocked: Vec<String>,
+    /// Subdomains only the given user (verified email or token subject) can claim
+    #[serde(default)]
+    pub reservations: HashMap<String, Vec<String>>,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
 pub enum ENV {
     Dev,
@@ -54,6 +98,7 @@ impl From<&str> for ENV {
 pub struct Settings {
     pub server: Server,
     pub auth: AuthRules,
+    pub subdomains: SubdomainRules,
     pub log: Log,
     pub env: ENV,
 }
@@ -0,0 +1,91 @@
+use shared_types::handshake::HandshakeError;
+use uuid::Uuid;
+
+use crate::settings;
+
+const MIN_LEN: usize = 3;
+const MAX_LEN: usize = 63;
+
+/// Check whether `user` may claim the subdomain `label`.
+///
+/// A label has to be a valid dns label of lowercase letters, digits and dashes,
+/// may not look like the random ids handed out to anonymous tunnels and may not
+/// be blocked in config. Labels reserved for a user in config are only handed
+/// to that user.
+pub fn check(
+    label: &str,
+    user: Option<&str>,
+    rules: &settings::SubdomainRules,
+) -> Result<(), HandshakeError> {
+    let invalid = |reason: &str| HandshakeError::InvalidSubdomain(format!("'{label}' {reason}"));
+    if !(MIN_LEN..=MAX_LEN).contains(&label.len()) {
+        return Err(invalid(&format!(
+            "must be between {MIN_LEN} and {MAX_LEN} characters long"
+        )));
+    }
+    if !label
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(invalid(
+            "may only contain lowercase letters, digits and dashes",
+        ));
+    }
+    if label.starts_with('-') || label.ends_with('-') {
+        return Err(invalid("may not start or end with a dash"));
+    }
+    if Uuid::parse_str(label).is_ok() {
+        return Err(invalid("is reserved for randomly assigned tunnels"));
+    }
+    if rules.blocked.iter().any(|blocked| blocked == label) {
+        return Err(HandshakeError::SubdomainUnavailable(label.into()));
+    }
+    let reserved_for_other = rules
+        .reservations
+        .iter()
+        .any(|(owner, labels)| Some(owner.as_str()) != user && labels.iter().any(|l| l == label));
+    if reserved_for_other {
+        return Err(HandshakeError::SubdomainUnavailable(label.into()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn rules() -> settings::SubdomainRules {
+        settings::SubdomainRules {
+            blocked: vec!["www".into()],
+            reservations: HashMap::from([("roald@example.com".into(), vec!["roald".into()])]),
+        }
+    }
+
+    #[test]
+    fn accepts_plain_labels() {
+        assert!(check("my-pr-123", None, &rules()).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        for label in ["ab", "-pr", "pr-", "My-PR", "pr.123", "pr_123"] {
+            assert!(matches!(
+                check(label, None, &rules()),
+                Err(HandshakeError::InvalidSubdomain(_))
+            ));
+        }
+        let uuid = Uuid::new_v4().to_string();
+        assert!(check(&uuid, None, &rules()).is_err());
+    }
+
+    #[test]
+    fn reservations_are_only_handed_to_their_owner() {
+        assert!(check("roald", Some("roald@example.com"), &rules()).is_ok());
+        assert!(matches!(
+            check("roald", Some("someone@example.com"), &rules()),
+            Err(HandshakeError::SubdomainUnavailable(_))
+        ));
+        assert!(check("www", Some("roald@example.com"), &rules()).is_err());
+    }
+}
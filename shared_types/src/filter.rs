@@ -0,0 +1,326 @@
+//! Narrowing down the captured traffic in the inspector.
+//!
+//! A [`TrafficFilter`] is built in the browser and evaluated next to the traffic
+//! store, so only the matching requests have to be sent over.
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{RequestCycle, RequestHead};
+
+/// Inclusive range of response statuses
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("'{0}' is not a status, expected something like 404, 4xx or 400-499")]
+pub struct InvalidStatusRange(String);
+
+impl FromStr for StatusRange {
+    type Err = InvalidStatusRange;
+
+    /// Accepts a single status, a class like `5xx` or a range like `400-499`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidStatusRange(s.to_owned());
+        let s = s.trim();
+        let range = if let Some((min, max)) = s.split_once('-') {
+            StatusRange {
+                min: min.trim().parse().map_err(|_| invalid())?,
+                max: max.trim().parse().map_err(|_| invalid())?,
+            }
+        } else if let Some(class) = s.to_ascii_lowercase().strip_suffix("xx") {
+            let class: u16 = class.parse().map_err(|_| invalid())?;
+            let min = class.checked_mul(100).ok_or_else(invalid)?;
+            StatusRange {
+                min,
+                max: min.saturating_add(99),
+            }
+        } else {
+            let status = s.parse().map_err(|_| invalid())?;
+            StatusRange {
+                min: status,
+                max: status,
+            }
+        };
+        match range.min <= range.max && range.max < 1000 {
+            true => Ok(range),
+            false => Err(invalid()),
+        }
+    }
+}
+
+/// Criteria a request cycle has to meet to be shown, unset criteria match anything
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrafficFilter {
+    /// Case insensitive search through the uri, headers and bodies
+    pub text: Option<String>,
+    pub method: Option<String>,
+    pub status: Option<StatusRange>,
+    /// Glob on the path of the request, `*` matches any run of characters and `?` a single one
+    pub path: Option<String>,
+    /// Case insensitive name of a request or response header
+    pub header_name: Option<String>,
+    /// Substring of a request or response header value, of the named header when one is given
+    pub header_value: Option<String>,
+    /// Exact substring of the request or response body
+    pub body: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl TrafficFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == TrafficFilter::default()
+    }
+
+    pub fn matches(&self, cycle: &RequestCycle) -> bool {
+        let head = &cycle.request_head;
+        let headers = || {
+            head.headers
+                .iter()
+                .chain(cycle.response_head.headers.iter())
+        };
+        let bodies = [&cycle.request_body, &cycle.response_body];
+
+        if let Some(method) = &self.method {
+            if !head.method.eq_ignore_ascii_case(method.trim()) {
+                return false;
+            }
+        }
+        if let Some(StatusRange { min, max }) = self.status {
+            if !(min..=max).contains(&cycle.response_head.status) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.path {
+            let path = head.uri.split('?').next().unwrap_or_default();
+            if !glob_matches(pattern, path) {
+                return false;
+            }
+        }
+        if self.header_name.is_some() || self.header_value.is_some() {
+            let found = headers().any(|(name, value)| {
+                self.header_name
+                    .as_ref()
+                    .is_none_or(|wanted| name.eq_ignore_ascii_case(wanted))
+                    && self
+                        .header_value
+                        .as_ref()
+                        .is_none_or(|wanted| value.contains(wanted.as_str()))
+            });
+            if !found {
+                return false;
+            }
+        }
+        if let Some(needle) = &self.body {
+            if !bodies.iter().any(|body| contains(body, needle.as_bytes())) {
+                return false;
+            }
+        }
+        if self.since.is_some_and(|since| cycle.timestamp_in < since)
+            || self.until.is_some_and(|until| cycle.timestamp_in > until)
+        {
+            return false;
+        }
+        if let Some(text) = &self.text {
+            let needle = text.to_lowercase();
+            let found = head.uri.to_lowercase().contains(&needle)
+                || headers().any(|(name, value)| {
+                    name.to_lowercase().contains(&needle) || value.to_lowercase().contains(&needle)
+                })
+                || bodies.iter().any(|body| {
+                    String::from_utf8_lossy(body)
+                        .to_lowercase()
+                        .contains(&needle)
+                });
+            if !found {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Criteria on the head of a request alone, for deciding on a request before it
+/// is answered. Unset criteria match any request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestMatch {
+    pub method: Option<String>,
+    /// Glob on the path of the request, like [`TrafficFilter::path`]
+    pub path: Option<String>,
+    /// Case insensitive name of a request header
+    pub header_name: Option<String>,
+    /// Substring of a request header value, of the named header when one is given
+    pub header_value: Option<String>,
+}
+
+impl RequestMatch {
+    pub fn matches(&self, head: &RequestHead) -> bool {
+        let path = head.uri.split('?').next().unwrap_or_default();
+        self.method
+            .as_ref()
+            .is_none_or(|method| head.method.eq_ignore_ascii_case(method.trim()))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|pattern| glob_matches(pattern, path))
+            && (self.header_name.is_none() && self.header_value.is_none()
+                || head.headers.iter().any(|(name, value)| {
+                    self.header_name
+                        .as_ref()
+                        .is_none_or(|wanted| name.eq_ignore_ascii_case(wanted))
+                        && self
+                            .header_value
+                            .as_ref()
+                            .is_none_or(|wanted| value.contains(wanted.as_str()))
+                }))
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
+
+/// Match `text` against a glob with `*` and `?` wildcards
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was seen and the text position it is currently covering up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+            }
+            Some(c) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // Let the last `*` swallow one more character and try again from there
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestHead, ResponseHead};
+
+    fn cycle() -> RequestCycle {
+        let timestamp = "2024-05-01T12:00:00Z".parse().unwrap();
+        RequestCycle {
+            id: 1,
+            timestamp_in: timestamp,
+            request_head: RequestHead {
+                method: "POST".into(),
+                uri: "/hooks/github/push?delivery=7".into(),
+                headers: vec![("X-GitHub-Event".into(), "push".into())],
+            },
+            request_body: br#"{"ref": "refs/heads/main"}"#.to_vec(),
+            timestamp_out: timestamp,
+            response_head: ResponseHead {
+                status: 502,
+                headers: vec![],
+            },
+            ..RequestCycle::default()
+        }
+    }
+
+    #[test]
+    fn status_ranges_parse() {
+        let range = |min, max| Ok(StatusRange { min, max });
+        assert_eq!("404".parse(), range(404, 404));
+        assert_eq!("5XX".parse(), range(500, 599));
+        assert_eq!("400 - 499".parse(), range(400, 499));
+        assert!("499-400".parse::<StatusRange>().is_err());
+        assert!("teapot".parse::<StatusRange>().is_err());
+        assert!("700xx".parse::<StatusRange>().is_err());
+        assert!("655xx".parse::<StatusRange>().is_err());
+    }
+
+    #[test]
+    fn globs_match_paths() {
+        assert!(glob_matches("/hooks/*", "/hooks/github/push"));
+        assert!(glob_matches("/hooks/*/push", "/hooks/github/push"));
+        assert!(glob_matches("*push", "/hooks/github/push"));
+        assert!(glob_matches("/hooks/git?ub/*", "/hooks/github/push"));
+        assert!(!glob_matches("/hooks/*/pull", "/hooks/github/push"));
+        assert!(!glob_matches("/hooks", "/hooks/github/push"));
+    }
+
+    #[test]
+    fn request_matches_look_at_the_head() {
+        let head = &cycle().request_head;
+        assert!(RequestMatch::default().matches(head));
+        let request = RequestMatch {
+            method: Some("post".into()),
+            path: Some("/hooks/*".into()),
+            header_name: Some("x-github-event".into()),
+            header_value: None,
+        };
+        assert!(request.matches(head));
+        let other_header = RequestMatch {
+            header_value: Some("pull".into()),
+            ..request.clone()
+        };
+        assert!(!other_header.matches(head));
+        let other_path = RequestMatch {
+            path: Some("/api/*".into()),
+            ..request
+        };
+        assert!(!other_path.matches(head));
+    }
+
+    #[test]
+    fn every_criterion_has_to_match() {
+        let cycle = cycle();
+        assert!(TrafficFilter::default().matches(&cycle));
+        let filter = TrafficFilter {
+            text: Some("REFS/HEADS".into()),
+            method: Some("post".into()),
+            status: Some("5xx".parse().unwrap()),
+            path: Some("/hooks/*".into()),
+            header_name: Some("x-github-event".into()),
+            header_value: Some("push".into()),
+            body: Some("main".into()),
+            since: Some("2024-05-01T11:00:00Z".parse().unwrap()),
+            until: None,
+        };
+        assert!(filter.matches(&cycle));
+        let header_mismatch = TrafficFilter {
+            header_value: Some("pull".into()),
+            ..filter.clone()
+        };
+        assert!(!header_mismatch.matches(&cycle));
+        let too_early = TrafficFilter {
+            until: Some("2024-05-01T11:00:00Z".parse().unwrap()),
+            ..filter
+        };
+        assert!(!too_early.matches(&cycle));
+    }
+}
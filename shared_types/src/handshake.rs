@@ -0,0 +1,271 @@
+//! The handshake a client and a server exchange over the first bidirectional
+//! stream of a quic connection.
+//!
+//! Every message is framed as the magic bytes `SGRK`, a big endian `u16`
+//! protocol version, a big endian `u32` payload length and a json payload. The
+//! version in the frame lets a peer refuse a message it cannot read instead of
+//! misinterpreting it, and the json payload lets newer peers add fields that
+//! older peers simply ignore.
+//!
+//! After the handshake every bidirectional stream the server opens starts with
+//! the big endian [`TunnelId`] of the tunnel the stream belongs to. For tcp
+//! tunnels that asked for [`TunnelRequest::peer_address`] a PROXY protocol
+//! version 2 header follows, see [`crate::proxy_protocol`].
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const MAGIC: &[u8; 4] = b"SGRK";
+/// The protocol version this build speaks
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest protocol version this build still understands. Peers speaking
+/// anything older are turned away with a [`HandshakeError::UnsupportedVersion`].
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// The handshake of the first releases: a mode byte and a bare token without a
+/// frame around them, answered with nothing but the address of a single tunnel.
+/// Those clients can not read a frame, so they are refused in plain text.
+pub const UNFRAMED_PROTOCOL_VERSION: u16 = 1;
+/// Since JWT's have to fit in a header 8kb is the practical upper limit on token size,
+/// this leaves plenty of room for everything else in a handshake message.
+pub const MAX_FRAME_LEN: usize = 16 * 1024;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+
+/// Index of a tunnel in the [`ClientHello`] that requested it
+pub type TunnelId = u16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Http,
+    Tcp,
+}
+
+/// Sent by the client right after opening the handshake stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// JWT proving who the client is, empty when the client has none
+    pub token: String,
+    pub tunnels: Vec<TunnelRequest>,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default)]
+    pub client: ClientMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelRequest {
+    pub mode: Mode,
+    /// Human readable subdomain for an http tunnel, a random one is assigned otherwise
+    #[serde(default)]
+    pub subdomain: Option<String>,
+    #[serde(default)]
+    pub access: AccessPolicy,
+    /// Start every stream of a tcp tunnel with a PROXY protocol version 2 header
+    /// carrying the visitor's address, see [`Capability::PeerAddress`]
+    #[serde(default)]
+    pub peer_address: bool,
+}
+
+/// Who may reach a tunnel. The server enforces this before any traffic enters
+/// the quic connection, an empty policy lets everyone in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessPolicy {
+    /// Credentials visitors of an http tunnel have to send as basic auth
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+    /// Networks visitors have to come from in CIDR notation, e.g. `10.0.0.0/8`
+    #[serde(default)]
+    pub allow_cidrs: Vec<String>,
+    /// Require visitors of an http tunnel to log in with a token the server trusts
+    #[serde(default)]
+    pub login: Option<LoginGate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginGate {
+    /// Users (verified email or token subject) that may visit, anyone the
+    /// server's auth rules accept when empty
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Things a client can ask of the server on top of plain tunnels.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Options {
+    /// Resume token of a previous session, to reclaim its addresses
+    #[serde(default)]
+    pub resume_token: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientMetadata {
+    pub name: String,
+    pub version: String,
+    pub os: String,
+}
+
+/// The server's answer to a [`ClientHello`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub capabilities: Vec<Capability>,
+    pub result: Result<Accepted, HandshakeError>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Dropped clients can reclaim their address with a resume token
+    Resume,
+    /// Streams of tcp tunnels that asked for it start with the visitor's address
+    PeerAddress,
+    /// Anything a newer server supports that this build does not know about
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Accepted {
+    /// One assignment per requested tunnel, in the order they were requested
+    pub tunnels: Vec<TunnelAssignment>,
+    /// Present this on a reconnect to get the same addresses back
+    pub resume_token: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelAssignment {
+    pub id: TunnelId,
+    /// Where the rest of the world can reach the tunnel
+    pub public_url: String,
+    pub address: Address,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Address {
+    Http { subdomain: String },
+    Tcp { port: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+pub enum HandshakeError {
+    #[error("protocol version {requested} is not supported, the server speaks {min} to {max}")]
+    UnsupportedVersion { requested: u16, min: u16, max: u16 },
+    #[error("malformed handshake: {0}")]
+    Malformed(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("invalid subdomain: {0}")]
+    InvalidSubdomain(String),
+    #[error("subdomain '{0}' is not available")]
+    SubdomainUnavailable(String),
+    #[error("invalid access policy: {0}")]
+    InvalidAccessPolicy(String),
+    #[error("internal server error: {0}")]
+    Internal(String),
+}
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("not a storm grok handshake frame")]
+    BadMagic,
+    #[error("handshake frame is truncated")]
+    Truncated,
+    #[error("protocol version {0} is not supported")]
+    UnsupportedVersion(u16),
+    #[error("could not read handshake payload: {0}")]
+    Payload(#[from] serde_json::Error),
+}
+
+/// Frame `message` for the given protocol version.
+pub fn encode<T: Serialize>(version: u16, message: &T) -> Vec<u8> {
+    let payload = serde_json::to_vec(message).expect("handshake messages always serialize");
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(MAGIC);
+    frame.extend_from_slice(&version.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Read a framed message, returning the protocol version it was sent with.
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<(u16, T), FrameError> {
+    if frame.len() < HEADER_LEN {
+        return Err(
+            match frame.starts_with(&MAGIC[..frame.len().min(MAGIC.len())]) {
+                true => FrameError::Truncated,
+                false => FrameError::BadMagic,
+            },
+        );
+    }
+    if &frame[..MAGIC.len()] != MAGIC {
+        return Err(FrameError::BadMagic);
+    }
+    let version = u16::from_be_bytes([frame[4], frame[5]]);
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(FrameError::UnsupportedVersion(version));
+    }
+    let len = u32::from_be_bytes([frame[6], frame[7], frame[8], frame[9]]) as usize;
+    let payload = frame
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .ok_or(FrameError::Truncated)?;
+    Ok((version, serde_json::from_slice(payload)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_survives_a_round_trip() {
+        let hello = ClientHello {
+            token: "ey.token".into(),
+            tunnels: vec![TunnelRequest {
+                mode: Mode::Tcp,
+                subdomain: None,
+                access: AccessPolicy::default(),
+                peer_address: true,
+            }],
+            options: Options {
+                resume_token: Some(Uuid::nil()),
+            },
+            client: ClientMetadata::default(),
+        };
+        let (version, decoded): (u16, ClientHello) =
+            decode(&encode(PROTOCOL_VERSION, &hello)).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(decoded.tunnels[0].mode, Mode::Tcp);
+        assert!(decoded.tunnels[0].peer_address);
+        assert_eq!(decoded.options.resume_token, Some(Uuid::nil()));
+    }
+
+    #[test]
+    fn legacy_handshake_is_rejected() {
+        let legacy = b"hey.some.jwt";
+        assert!(matches!(
+            decode::<ClientHello>(legacy),
+            Err(FrameError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn future_version_is_rejected_before_parsing() {
+        let frame = encode(PROTOCOL_VERSION + 1, &"anything");
+        assert!(matches!(
+            decode::<ClientHello>(&frame),
+            Err(FrameError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn unknown_capabilities_are_tolerated() {
+        let capabilities: Vec<Capability> =
+            serde_json::from_str(r#"["resume", "teleport"]"#).unwrap();
+        assert_eq!(capabilities, vec![Capability::Resume, Capability::Unknown]);
+    }
+}
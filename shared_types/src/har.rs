@@ -0,0 +1,505 @@
+//! Conversion between captured traffic and HAR 1.2, the format browser devtools
+//! import and export network traffic in.
+//!
+//! Besides the fields the spec requires every exported entry carries the custom
+//! `_ports` field, so traffic exported from storm grok can be replayed against
+//! the same tunnel after importing it again.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{BodySizes, Outcome, RequestCycle, RequestHead, ResponseHead, TunnelPorts};
+
+pub const HAR_VERSION: &str = "1.2";
+const HTTP_VERSION: &str = "HTTP/1.1";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Har {
+    pub log: Log,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Log {
+    pub version: String,
+    pub creator: Creator,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Creator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub started_date_time: DateTime<Utc>,
+    /// Milliseconds between sending the request and receiving the full response
+    pub time: f64,
+    pub request: Request,
+    pub response: Response,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub timings: Timings,
+    #[serde(rename = "_ports", default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<TunnelPorts>,
+    /// Set for failed exchanges, so storm grok can tell them apart on import
+    #[serde(rename = "_outcome", default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+    /// Chrome notes why a request failed here
+    #[serde(rename = "_error", default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Names of the client rules that answered or rewrote the exchange
+    #[serde(rename = "_rules", default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<NameValue>,
+    #[serde(default)]
+    pub headers: Vec<NameValue>,
+    #[serde(default)]
+    pub query_string: Vec<NameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<PostData>,
+    #[serde(default)]
+    pub headers_size: i64,
+    #[serde(default)]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostData {
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub text: String,
+    /// Not in the spec for request bodies, but needed to carry binary ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    pub status: u16,
+    #[serde(default)]
+    pub status_text: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<NameValue>,
+    #[serde(default)]
+    pub headers: Vec<NameValue>,
+    pub content: Content,
+    #[serde(rename = "redirectURL", default)]
+    pub redirect_url: String,
+    #[serde(default)]
+    pub headers_size: i64,
+    #[serde(default)]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+    pub size: i64,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cache {}
+
+/// HAR marks phases that were not measured with -1
+const NOT_MEASURED: f64 = -1.0;
+
+fn not_measured() -> f64 {
+    NOT_MEASURED
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    #[serde(default = "not_measured")]
+    pub connect: f64,
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+}
+
+impl Timings {
+    fn from_cycle(timings: &crate::Timings, time: f64) -> Self {
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        let connect = timings.connected.map_or(NOT_MEASURED, ms);
+        match (timings.first_byte, timings.done) {
+            (Some(first_byte), Some(done)) => Timings {
+                connect,
+                send: 0.0,
+                wait: ms(first_byte.saturating_sub(timings.connected.unwrap_or(0))),
+                receive: ms(done.saturating_sub(first_byte)),
+            },
+            _ => Timings {
+                connect,
+                send: 0.0,
+                wait: time,
+                receive: 0.0,
+            },
+        }
+    }
+
+    fn into_cycle(self, time: f64) -> crate::Timings {
+        let micros = |ms: f64| (ms.max(0.0) * 1000.0) as u64;
+        let connected = (self.connect >= 0.0).then(|| micros(self.connect));
+        crate::Timings {
+            connected,
+            first_byte: Some(connected.unwrap_or(0) + micros(self.send) + micros(self.wait)),
+            done: Some(micros(time)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HarError {
+    #[error("{0} does not say which tunnel it came through and there is no http tunnel to replay it against")]
+    UnknownTunnel(String),
+    #[error("body of {0} is not valid base64: {1}")]
+    Body(String, base64::DecodeError),
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn name_values(headers: &[(String, String)]) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+/// Bodies that are not utf-8 are base64 encoded
+fn encode_body(body: &[u8]) -> (String, Option<String>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_owned(), None),
+        Err(_) => (STANDARD.encode(body), Some("base64".into())),
+    }
+}
+
+fn decode_body(text: &str, encoding: Option<&str>) -> Result<Vec<u8>, base64::DecodeError> {
+    match encoding {
+        Some("base64") => STANDARD.decode(text),
+        _ => Ok(text.as_bytes().to_vec()),
+    }
+}
+
+/// Captured uris are usually just a path, HAR wants the full url
+fn absolute_url(head: &RequestHead) -> String {
+    if head.uri.starts_with("http://") || head.uri.starts_with("https://") {
+        return head.uri.clone();
+    }
+    let host = header(&head.headers, "host").unwrap_or("localhost");
+    format!("http://{host}{}", head.uri)
+}
+
+/// Split a url in its authority and its path and query, dropping any fragment
+fn split_url(url: &str) -> (Option<&str>, &str) {
+    let url = url.split('#').next().unwrap_or_default();
+    match url.split_once("://") {
+        Some((_, rest)) => match rest.find('/') {
+            Some(i) => (Some(&rest[..i]), &rest[i..]),
+            None => (Some(rest), "/"),
+        },
+        None => (None, url),
+    }
+}
+
+fn query_string(uri: &str) -> Vec<NameValue> {
+    let query = match uri.split_once('?') {
+        Some((_, query)) => query,
+        None => return vec![],
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            NameValue {
+                name: name.into(),
+                value: value.into(),
+            }
+        })
+        .collect()
+}
+
+impl Entry {
+    fn from_cycle(cycle: &RequestCycle) -> Self {
+        let time = match cycle.timings.done {
+            Some(done) => done as f64 / 1000.0,
+            None => (cycle.timestamp_out - cycle.timestamp_in).num_milliseconds() as f64,
+        };
+        let post_data = (!cycle.request_body.is_empty()).then(|| {
+            let (text, encoding) = encode_body(&cycle.request_body);
+            PostData {
+                mime_type: header(&cycle.request_head.headers, "content-type")
+                    .unwrap_or_default()
+                    .into(),
+                text,
+                encoding,
+            }
+        });
+        let (text, encoding) = encode_body(&cycle.response_body);
+        let failed = cycle.outcome.is_failure();
+        Entry {
+            started_date_time: cycle.timestamp_in,
+            time,
+            request: Request {
+                method: cycle.request_head.method.clone(),
+                url: absolute_url(&cycle.request_head),
+                http_version: HTTP_VERSION.into(),
+                cookies: vec![],
+                headers: name_values(&cycle.request_head.headers),
+                query_string: query_string(&cycle.request_head.uri),
+                post_data,
+                headers_size: -1,
+                body_size: cycle.sizes.request.max(cycle.request_body.len() as u64) as i64,
+            },
+            response: Response {
+                status: cycle.response_head.status,
+                status_text: String::new(),
+                http_version: HTTP_VERSION.into(),
+                cookies: vec![],
+                headers: name_values(&cycle.response_head.headers),
+                content: Content {
+                    size: cycle.response_body.len() as i64,
+                    mime_type: header(&cycle.response_head.headers, "content-type")
+                        .unwrap_or_default()
+                        .into(),
+                    text: Some(text),
+                    encoding,
+                },
+                redirect_url: header(&cycle.response_head.headers, "location")
+                    .unwrap_or_default()
+                    .into(),
+                headers_size: -1,
+                body_size: cycle.sizes.response.max(cycle.response_body.len() as u64) as i64,
+            },
+            cache: Cache {},
+            timings: Timings::from_cycle(&cycle.timings, time),
+            ports: Some(cycle.ports.clone()),
+            outcome: failed.then(|| cycle.outcome.clone()),
+            error: failed.then(|| cycle.outcome.to_string()),
+            rules: cycle.rules.clone(),
+        }
+    }
+
+    fn into_cycle(self, default_ports: Option<TunnelPorts>) -> Result<RequestCycle, HarError> {
+        let url = self.request.url;
+        let ports = self
+            .ports
+            .or(default_ports)
+            .ok_or_else(|| HarError::UnknownTunnel(url.clone()))?;
+        let (authority, uri) = split_url(&url);
+        // Browsers record http/2 pseudo headers like `:authority`, those can not be replayed
+        let mut headers: Vec<(String, String)> = self
+            .request
+            .headers
+            .into_iter()
+            .filter(|h| !h.name.starts_with(':'))
+            .map(|h| (h.name, h.value))
+            .collect();
+        if let (None, Some(authority)) = (header(&headers, "host"), authority) {
+            headers.insert(0, ("host".into(), authority.into()));
+        }
+        let request_body = match self.request.post_data {
+            Some(post_data) => decode_body(&post_data.text, post_data.encoding.as_deref())
+                .map_err(|e| HarError::Body(url.clone(), e))?,
+            None => vec![],
+        };
+        let response_body = match self.response.content.text {
+            Some(text) => decode_body(&text, self.response.content.encoding.as_deref())
+                .map_err(|e| HarError::Body(url.clone(), e))?,
+            None => vec![],
+        };
+        // Browsers do not always know the body size, -1 or leaving it out both happen
+        let size = |body_size: i64, body: &[u8]| body_size.max(0).max(body.len() as i64) as u64;
+        let sizes = BodySizes {
+            request: size(self.request.body_size, &request_body),
+            response: size(self.response.body_size, &response_body),
+        };
+        Ok(RequestCycle {
+            // The store hands out ids when the cycles are imported
+            id: 0,
+            timestamp_in: self.started_date_time,
+            request_head: RequestHead {
+                method: self.request.method,
+                uri: uri.into(),
+                headers,
+            },
+            request_body,
+            timestamp_out: self.started_date_time + Duration::milliseconds(self.time as i64),
+            response_head: ResponseHead {
+                status: self.response.status,
+                headers: self
+                    .response
+                    .headers
+                    .into_iter()
+                    .map(|h| (h.name, h.value))
+                    .collect(),
+            },
+            response_body,
+            ports,
+            outcome: match (self.outcome, self.error) {
+                (Some(outcome), _) => outcome,
+                (None, Some(error)) => Outcome::ProxyError(error),
+                (None, None) => Outcome::Completed,
+            },
+            timings: self.timings.into_cycle(self.time),
+            sizes,
+            rules: self.rules,
+        })
+    }
+}
+
+impl Har {
+    pub fn from_cycles<'a>(cycles: impl IntoIterator<Item = &'a RequestCycle>) -> Self {
+        Har {
+            log: Log {
+                version: HAR_VERSION.into(),
+                creator: Creator {
+                    name: "storm grok".into(),
+                    version: env!("CARGO_PKG_VERSION").into(),
+                },
+                entries: cycles.into_iter().map(Entry::from_cycle).collect(),
+            },
+        }
+    }
+
+    /// Turn the entries back into request cycles. Entries that do not say which
+    /// tunnel they came through, like the ones exported by a browser, are
+    /// assigned to `default_ports`.
+    pub fn into_cycles(
+        self,
+        default_ports: Option<TunnelPorts>,
+    ) -> Result<Vec<RequestCycle>, HarError> {
+        self.log
+            .entries
+            .into_iter()
+            .map(|entry| entry.into_cycle(default_ports.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports() -> TunnelPorts {
+        TunnelPorts {
+            target_port: 3000,
+            proxy_port: 6190,
+            ..TunnelPorts::default()
+        }
+    }
+
+    #[test]
+    fn cycles_survive_a_round_trip() {
+        let now = Utc::now();
+        let cycle = RequestCycle {
+            id: 1,
+            timestamp_in: now,
+            request_head: RequestHead {
+                method: "POST".into(),
+                uri: "/upload?name=cat&raw".into(),
+                headers: vec![("host".into(), "abc.stormgrok.nl".into())],
+            },
+            request_body: vec![0xff, 0x00, 0x10],
+            timestamp_out: now + Duration::milliseconds(12),
+            response_head: ResponseHead {
+                status: 201,
+                headers: vec![("content-type".into(), "text/plain".into())],
+            },
+            response_body: b"created".to_vec(),
+            ports: ports(),
+            outcome: Outcome::Timeout("read timed out".into()),
+            timings: crate::Timings {
+                connected: Some(1500),
+                first_byte: Some(9000),
+                done: Some(12000),
+            },
+            sizes: BodySizes {
+                request: 3,
+                response: 7,
+            },
+            rules: vec!["slow down".into()],
+        };
+        let har = Har::from_cycles([&cycle]);
+        let entry = &har.log.entries[0];
+        assert_eq!(
+            entry.request.url,
+            "http://abc.stormgrok.nl/upload?name=cat&raw"
+        );
+        assert_eq!(entry.request.query_string.len(), 2);
+        assert_eq!(entry.response.content.text.as_deref(), Some("created"));
+
+        let json = serde_json::to_string(&har).unwrap();
+        let har: Har = serde_json::from_str(&json).unwrap();
+        let imported = har.into_cycles(None).unwrap().remove(0);
+        assert_eq!(imported.request_head.uri, cycle.request_head.uri);
+        assert_eq!(imported.request_body, cycle.request_body);
+        assert_eq!(imported.response_body, cycle.response_body);
+        assert_eq!(
+            imported.timestamp_out - imported.timestamp_in,
+            Duration::milliseconds(12)
+        );
+        assert_eq!(imported.ports, ports());
+        assert_eq!(imported.outcome, cycle.outcome);
+        assert_eq!(imported.timings, cycle.timings);
+        assert_eq!(imported.sizes, cycle.sizes);
+        assert_eq!(imported.rules, cycle.rules);
+    }
+
+    #[test]
+    fn browser_entries_are_assigned_a_tunnel() {
+        let json = r#"{"log": {"version": "1.2", "creator": {"name": "Firefox", "version": "125"},
+            "entries": [{"startedDateTime": "2024-05-01T12:00:00.000+02:00", "time": 5,
+                "request": {"method": "GET", "url": "https://example.com/a?b=c#top",
+                    "headers": [{"name": ":authority", "value": "example.com"}]},
+                "response": {"status": 200, "content": {"size": 0}}}]}}"#;
+        let har: Har = serde_json::from_str(json).unwrap();
+        assert!(har.clone().into_cycles(None).is_err());
+        let cycle = har.into_cycles(Some(ports())).unwrap().remove(0);
+        assert_eq!(cycle.request_head.uri, "/a?b=c");
+        assert_eq!(
+            cycle.request_head.headers,
+            vec![("host".to_string(), "example.com".to_string())]
+        );
+        assert_eq!(cycle.ports, ports());
+    }
+}
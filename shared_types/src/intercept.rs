@@ -0,0 +1,91 @@
+//! Breakpoints that pause exchanges in the eaves proxy until they are released
+//! or dropped from the inspector, optionally with an edited head and body.
+use serde::{Deserialize, Serialize};
+
+use crate::{filter::RequestMatch, rules::BODY_HEADERS, RequestHead, ResponseHead, TunnelPorts};
+
+/// Which exchanges to pause
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BreakRule {
+    #[serde(flatten)]
+    pub request: RequestMatch,
+    /// Pause matching requests before they reach the local service
+    pub requests: bool,
+    /// Pause the responses to matching requests before they go back through the tunnel
+    pub responses: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// On its way to the local service
+    Request,
+    /// On its way back to the visitor
+    Response,
+}
+
+/// An exchange waiting at a breakpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Paused {
+    pub id: u64,
+    pub phase: Phase,
+    pub request_head: RequestHead,
+    /// Only there for paused responses
+    pub response_head: Option<ResponseHead>,
+    pub ports: TunnelPorts,
+    /// Body of a paused request, `None` when it streams through unchanged
+    /// because it is chunked or too big to hold. Paused responses have none,
+    /// their body has not arrived yet.
+    #[serde(default)]
+    pub request_body: Option<Vec<u8>>,
+}
+
+/// What to do with a paused exchange
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Verdict {
+    /// Send the request on to the local service with this head, and this body
+    /// when the paused request came with one
+    ReleaseRequest {
+        head: RequestHead,
+        body: Option<Vec<u8>>,
+    },
+    /// Send the response back with this head, and this body instead of the one
+    /// from the local service when there is one
+    ReleaseResponse {
+        head: ResponseHead,
+        body: Option<Vec<u8>>,
+    },
+    /// Never let it through, the visitor gets an error instead
+    Drop,
+}
+
+/// Swap the headers that describe the original body for the length of the edited
+/// one, which goes out as it was typed
+pub fn fit_to_body(headers: &mut Vec<(String, String)>, body: &[u8]) {
+    headers.retain(|(name, _)| !BODY_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+    headers.push(("content-length".into(), body.len().to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edited_bodies_are_sent_with_their_own_length() {
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Transfer-Encoding".to_string(), "chunked".to_string()),
+            ("Content-Encoding".to_string(), "gzip".to_string()),
+            ("Content-Length".to_string(), "2".to_string()),
+        ];
+        fit_to_body(&mut headers, b"{\"ok\":true}");
+        assert_eq!(
+            headers,
+            vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("content-length".to_string(), "11".to_string()),
+            ]
+        );
+    }
+}
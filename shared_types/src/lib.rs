@@ -1,3 +1,5 @@
+use std::fmt;
+
 use chrono::Utc;
 
 use chrono::prelude::*;
@@ -6,28 +8,168 @@ use serde::{Deserialize, Serialize};
 
 use base64_serde::base64_serde_type;
 
+pub mod filter;
+pub mod handshake;
+pub mod har;
+pub mod intercept;
+pub mod proxy_protocol;
+pub mod rules;
+pub mod snippet;
+
 base64_serde_type!(Base64Standard, base64::engine::general_purpose::STANDARD);
 
+/// A slice of the captured traffic, ordered oldest request first
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TrafficLog {
+pub struct TrafficPage {
     pub requests: Vec<RequestCycle>,
+    /// Number of requests stored in total
+    pub total: usize,
+    /// Number of newer requests that were skipped to get to this page
+    pub offset: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RequestHead {
     pub method: String,
     pub uri: String,
     pub headers: Vec<(String, String)>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ResponseHead {
     pub status: u16,
     pub headers: Vec<(String, String)>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where a request went on the client: the local service and the eaves proxy in front of it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelPorts {
+    /// Port of the local service the tunnel forwards to
+    pub target_port: u16,
+    /// Port of the eaves proxy in front of it
+    pub proxy_port: u16,
+    /// Host of the local service, another machine when the tunnel was opened `--to` it
+    #[serde(default = "default_target_host")]
+    pub target_host: String,
+    /// The local service speaks https
+    #[serde(default)]
+    pub target_tls: bool,
+    /// The local service may present any certificate
+    #[serde(default)]
+    pub insecure_target: bool,
+}
+
+fn default_target_host() -> String {
+    "127.0.0.1".into()
+}
+
+impl Default for TunnelPorts {
+    fn default() -> Self {
+        TunnelPorts {
+            target_port: 0,
+            proxy_port: 0,
+            target_host: default_target_host(),
+            target_tls: false,
+            insecure_target: false,
+        }
+    }
+}
+
+impl TunnelPorts {
+    /// Url of `path_and_query` on the local service itself
+    pub fn target_url(&self, path_and_query: &str) -> String {
+        let host = match self.target_host.contains(':') {
+            true => format!("[{}]", self.target_host),
+            false => self.target_host.clone(),
+        };
+        let scheme = match self.target_tls {
+            true => "https",
+            false => "http",
+        };
+        match (self.target_tls, self.target_port) {
+            (true, 443) | (false, 80) => format!("{scheme}://{host}{path_and_query}"),
+            _ => format!("{scheme}://{host}:{}{path_and_query}", self.target_port),
+        }
+    }
+}
+
+/// How an exchange through the eaves proxy ended
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "reason")]
+pub enum Outcome {
+    #[default]
+    Completed,
+    /// The local service could not be reached
+    UpstreamConnect(String),
+    Timeout(String),
+    /// The visitor went away before the exchange was done
+    ClientAbort(String),
+    ProxyError(String),
+    /// Dropped at a breakpoint in the inspector
+    Dropped,
+}
+
+impl Outcome {
+    pub fn is_failure(&self) -> bool {
+        *self != Outcome::Completed
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Completed => write!(f, "completed"),
+            Outcome::UpstreamConnect(reason) => {
+                write!(f, "could not connect to the local service: {reason}")
+            }
+            Outcome::Timeout(reason) => write!(f, "timed out: {reason}"),
+            Outcome::ClientAbort(reason) => write!(f, "aborted by the client: {reason}"),
+            Outcome::ProxyError(reason) => write!(f, "proxy error: {reason}"),
+            Outcome::Dropped => write!(f, "dropped at a breakpoint"),
+        }
+    }
+}
+
+/// Where the time of an exchange went, in microseconds after the request came in
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timings {
+    /// A connection to the local service was ready
+    pub connected: Option<u64>,
+    /// The head of the response came back
+    pub first_byte: Option<u64>,
+    /// The last byte of the response was passed on
+    pub done: Option<u64>,
+}
+
+impl Timings {
+    /// The measured phases of the exchange as name, start and end
+    pub fn phases(&self) -> Vec<(&'static str, u64, u64)> {
+        let mut phases = vec![];
+        if let Some(connected) = self.connected {
+            phases.push(("connect", 0, connected));
+        }
+        if let Some(first_byte) = self.first_byte {
+            phases.push(("wait", self.connected.unwrap_or(0), first_byte));
+            if let Some(done) = self.done {
+                phases.push(("receive", first_byte, done));
+            }
+        }
+        phases
+    }
+}
+
+/// Sizes of the bodies as they went through, captured bodies can be cut short
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BodySizes {
+    pub request: u64,
+    pub response: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RequestCycle {
+    /// Handed out by the traffic store in increasing order, 0 until a cycle is given one
+    #[serde(default)]
+    pub id: u64,
     #[serde(with = "ts_milliseconds")]
     pub timestamp_in: DateTime<Utc>,
     pub request_head: RequestHead,
@@ -38,10 +180,20 @@ pub struct RequestCycle {
     pub response_head: ResponseHead,
     #[serde(with = "Base64Standard")]
     pub response_body: Vec<u8>,
+    pub ports: TunnelPorts,
+    #[serde(default)]
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub timings: Timings,
+    #[serde(default)]
+    pub sizes: BodySizes,
+    /// Names of the configured rules that answered or rewrote the exchange
+    #[serde(default)]
+    pub rules: Vec<String>,
 }
 
 impl PartialEq for RequestCycle {
     fn eq(&self, other: &Self) -> bool {
-        self.timestamp_in == other.timestamp_in
+        self.id == other.id
     }
 }
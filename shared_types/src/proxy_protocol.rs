@@ -0,0 +1,251 @@
+//! The PROXY protocol, which tells a service behind a proxy who really connected
+//! by sending a header ahead of the stream itself.
+//!
+//! The server reads it from trusted load balancers in front of tcp tunnels and
+//! starts streams with a version 2 header when a tunnel asks for the visitor's
+//! address. The client passes that on to the local service in the version it
+//! was configured with.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use thiserror::Error;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// A version 1 header is at most 107 bytes, the CRLF included
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, family and protocol, and the length of the rest
+pub const V2_FIXED_LEN: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Version {
+    /// Human readable, understood by nearly everything that speaks the protocol
+    V1,
+    /// Binary
+    V2,
+}
+
+/// Who connected to whom, as seen by the proxy that sent the header
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Addresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    /// The header goes on past the bytes read so far
+    Incomplete,
+    /// A header of `len` bytes, without addresses when the proxy did not know
+    /// them or connected on its own behalf
+    Header {
+        addresses: Option<Addresses>,
+        len: usize,
+    },
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid PROXY protocol header: {0}")]
+pub struct InvalidHeader(&'static str);
+
+impl Addresses {
+    pub fn encode(&self, version: Version) -> Vec<u8> {
+        match version {
+            Version::V1 => {
+                // Both addresses have to be of the same family
+                let (source, destination) = match (self.source.ip(), self.destination.ip()) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        (IpAddr::V4(source), IpAddr::V4(destination))
+                    }
+                    (source, destination) => (IpAddr::V6(v6(source)), IpAddr::V6(v6(destination))),
+                };
+                let family = match source.is_ipv4() {
+                    true => "TCP4",
+                    false => "TCP6",
+                };
+                format!(
+                    "PROXY {family} {source} {destination} {} {}\r\n",
+                    self.source.port(),
+                    self.destination.port()
+                )
+                .into_bytes()
+            }
+            Version::V2 => {
+                let mut addresses = vec![];
+                let family = match (self.source.ip(), self.destination.ip()) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        addresses.extend(source.octets());
+                        addresses.extend(destination.octets());
+                        // TCP over IPv4
+                        0x11
+                    }
+                    (source, destination) => {
+                        addresses.extend(v6(source).octets());
+                        addresses.extend(v6(destination).octets());
+                        // TCP over IPv6
+                        0x21
+                    }
+                };
+                addresses.extend(self.source.port().to_be_bytes());
+                addresses.extend(self.destination.port().to_be_bytes());
+
+                let mut header = V2_SIGNATURE.to_vec();
+                // Version 2 with the PROXY command
+                header.push(0x21);
+                header.push(family);
+                header.extend((addresses.len() as u16).to_be_bytes());
+                header.extend(addresses);
+                header
+            }
+        }
+    }
+}
+
+fn v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// Total length of a version 2 header, from its fixed part
+pub fn v2_len(fixed: &[u8; V2_FIXED_LEN]) -> usize {
+    V2_FIXED_LEN + u16::from_be_bytes([fixed[14], fixed[15]]) as usize
+}
+
+/// Read the header at the start of `buf`, in either version
+pub fn decode(buf: &[u8]) -> Result<Decoded, InvalidHeader> {
+    let starts_with = |prefix: &[u8]| {
+        let len = buf.len().min(prefix.len());
+        buf[..len] == prefix[..len]
+    };
+    if starts_with(V2_SIGNATURE) {
+        decode_v2(buf)
+    } else if starts_with(V1_PREFIX) {
+        decode_v1(buf)
+    } else {
+        Err(InvalidHeader("the stream does not start with one"))
+    }
+}
+
+fn decode_v1(buf: &[u8]) -> Result<Decoded, InvalidHeader> {
+    let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+        return match buf.len() < V1_MAX_LEN {
+            true => Ok(Decoded::Incomplete),
+            false => Err(InvalidHeader("version 1 header is too long")),
+        };
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Err(InvalidHeader("version 1 header is too long"));
+    }
+    let line = std::str::from_utf8(&buf[..end])
+        .map_err(|_| InvalidHeader("version 1 header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addresses = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| match (ip.parse(), port.parse()) {
+                (Ok(ip), Ok(port)) => Ok(SocketAddr::new(ip, port)),
+                _ => Err(InvalidHeader("version 1 header has an invalid address")),
+            };
+            Some(Addresses {
+                source: address(source, source_port)?,
+                destination: address(destination, destination_port)?,
+            })
+        }
+        _ => return Err(InvalidHeader("version 1 header is malformed")),
+    };
+    Ok(Decoded::Header { addresses, len })
+}
+
+fn decode_v2(buf: &[u8]) -> Result<Decoded, InvalidHeader> {
+    let Some(fixed) = buf.first_chunk::<V2_FIXED_LEN>() else {
+        return Ok(Decoded::Incomplete);
+    };
+    let len = v2_len(fixed);
+    if buf.len() < len {
+        return Ok(Decoded::Incomplete);
+    }
+    if fixed[12] >> 4 != 2 {
+        return Err(InvalidHeader("only versions 1 and 2 exist"));
+    }
+    let rest = &buf[V2_FIXED_LEN..len];
+    let port = |at: usize| u16::from_be_bytes([rest[at], rest[at + 1]]);
+    let too_short = InvalidHeader("version 2 header is too short for its addresses");
+    let addresses = match (fixed[12] & 0x0f, fixed[13] >> 4) {
+        // LOCAL, the proxy connected on its own behalf, e.g. for a health check
+        (0x0, _) => None,
+        (0x1, 0x1) => {
+            let octets = rest.first_chunk::<12>().ok_or(too_short)?;
+            let ip = |at: usize| {
+                Ipv4Addr::new(octets[at], octets[at + 1], octets[at + 2], octets[at + 3])
+            };
+            Some(Addresses {
+                source: SocketAddr::new(ip(0).into(), port(8)),
+                destination: SocketAddr::new(ip(4).into(), port(10)),
+            })
+        }
+        (0x1, 0x2) => {
+            let octets = rest.first_chunk::<36>().ok_or(too_short)?;
+            let ip = |at: usize| {
+                let mut address = [0; 16];
+                address.copy_from_slice(&octets[at..at + 16]);
+                Ipv6Addr::from(address)
+            };
+            Some(Addresses {
+                source: SocketAddr::new(ip(0).into(), port(32)),
+                destination: SocketAddr::new(ip(16).into(), port(34)),
+            })
+        }
+        // Unspecified or unix sockets, nothing to go on
+        (0x1, _) => None,
+        _ => return Err(InvalidHeader("version 2 header has an unknown command")),
+    };
+    Ok(Decoded::Header { addresses, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_round_trip() {
+        let v4 = Addresses {
+            source: "203.0.113.7:51234".parse().unwrap(),
+            destination: "10.0.0.2:5432".parse().unwrap(),
+        };
+        let mixed = Addresses {
+            source: "[2001:db8::1]:51234".parse().unwrap(),
+            destination: "10.0.0.2:5432".parse().unwrap(),
+        };
+        assert_eq!(
+            v4.encode(Version::V1),
+            b"PROXY TCP4 203.0.113.7 10.0.0.2 51234 5432\r\n"
+        );
+        for version in [Version::V1, Version::V2] {
+            let mut stream = v4.encode(version);
+            let len = stream.len();
+            stream.extend(b"SELECT 1;");
+            assert_eq!(
+                decode(&stream),
+                Ok(Decoded::Header {
+                    addresses: Some(v4),
+                    len
+                })
+            );
+            assert_eq!(decode(&stream[..len - 1]), Ok(Decoded::Incomplete));
+
+            let Ok(Decoded::Header {
+                addresses: Some(decoded),
+                ..
+            }) = decode(&mixed.encode(version))
+            else {
+                panic!("mixed families should decode as IPv6");
+            };
+            assert_eq!(decoded.source, mixed.source);
+            assert_eq!(decoded.destination.port(), 5432);
+        }
+        assert!(decode(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(decode(b"PROXY TCP4 nonsense\r\n").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Rules from the client config that let the eaves proxy answer requests itself
+//! or rewrite what the local service answered.
+//!
+//! A request is answered by the first matching rule with a mock, it then never
+//! reaches the local service. Otherwise the rewrites of all matching rules are
+//! applied to the response, in the order the rules are configured.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{filter::RequestMatch, RequestHead, ResponseHead};
+
+/// Headers that describe the body as it was, they can not stay when it is replaced
+pub(crate) const BODY_HEADERS: &[&str] =
+    &["content-length", "content-encoding", "transfer-encoding"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyRule {
+    /// Shown on the requests the rule applied to
+    pub name: String,
+    #[serde(flatten)]
+    pub request: RequestMatch,
+    pub mock: Option<MockResponse>,
+    pub rewrite: Option<ResponseRewrite>,
+}
+
+/// A canned response to answer with instead of the local service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MockResponse {
+    #[serde(default = "ok")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn ok() -> u16 {
+    200
+}
+
+impl MockResponse {
+    pub fn head(&self) -> ResponseHead {
+        let mut headers: Vec<(String, String)> = self
+            .headers
+            .iter()
+            .filter(|(name, _)| !BODY_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        headers.push(("content-length".into(), self.body.len().to_string()));
+        ResponseHead {
+            status: self.status,
+            headers,
+        }
+    }
+}
+
+/// Changes to make to a response from the local service
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseRewrite {
+    pub status: Option<u16>,
+    /// Headers to add, replacing any with the same name
+    pub set_headers: BTreeMap<String, String>,
+    pub remove_headers: Vec<String>,
+    /// Body to send instead of the one from the local service
+    pub body: Option<String>,
+}
+
+impl ResponseRewrite {
+    pub fn apply(&self, head: &mut ResponseHead) {
+        if let Some(status) = self.status {
+            head.status = status;
+        }
+        let mut dropped: Vec<&str> = self.remove_headers.iter().map(String::as_str).collect();
+        dropped.extend(self.set_headers.keys().map(String::as_str));
+        if self.body.is_some() {
+            dropped.extend(BODY_HEADERS);
+        }
+        head.headers.retain(|(name, _)| {
+            !dropped
+                .iter()
+                .any(|dropped| name.eq_ignore_ascii_case(dropped))
+        });
+        head.headers.extend(
+            self.set_headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        if let Some(body) = &self.body {
+            head.headers
+                .push(("content-length".into(), body.len().to_string()));
+        }
+    }
+}
+
+/// Name and mock of the rule that answers the request itself, if any
+pub fn mock_for<'a>(
+    rules: &'a [ProxyRule],
+    head: &RequestHead,
+) -> Option<(&'a str, &'a MockResponse)> {
+    rules
+        .iter()
+        .filter(|rule| rule.request.matches(head))
+        .find_map(|rule| Some((rule.name.as_str(), rule.mock.as_ref()?)))
+}
+
+/// Names and rewrites of the rules that apply to the response, in the order to apply them
+pub fn rewrites_for<'a>(
+    rules: &'a [ProxyRule],
+    head: &'a RequestHead,
+) -> impl Iterator<Item = (&'a str, &'a ResponseRewrite)> {
+    rules
+        .iter()
+        .filter(move |rule| rule.request.matches(head))
+        .filter_map(|rule| Some((rule.name.as_str(), rule.rewrite.as_ref()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_replace_headers_and_body() {
+        let mut head = ResponseHead {
+            status: 500,
+            headers: vec![
+                ("Server".into(), "gunicorn".into()),
+                ("Content-Encoding".into(), "gzip".into()),
+                ("Content-Length".into(), "1234".into()),
+                ("x-trace".into(), "abc".into()),
+            ],
+        };
+        let rewrite = ResponseRewrite {
+            status: Some(200),
+            set_headers: BTreeMap::from([("x-trace".into(), "mocked".into())]),
+            remove_headers: vec!["server".into()],
+            body: Some("{}".into()),
+        };
+        rewrite.apply(&mut head);
+        assert_eq!(head.status, 200);
+        assert_eq!(
+            head.headers,
+            vec![
+                ("x-trace".into(), "mocked".into()),
+                ("content-length".into(), "2".into()),
+            ]
+        );
+
+        let rules = [
+            ProxyRule {
+                name: "inject".into(),
+                request: RequestMatch::default(),
+                mock: None,
+                rewrite: Some(rewrite),
+            },
+            ProxyRule {
+                name: "stripe".into(),
+                request: RequestMatch {
+                    path: Some("/hooks/stripe".into()),
+                    ..RequestMatch::default()
+                },
+                mock: Some(MockResponse {
+                    status: 202,
+                    headers: BTreeMap::new(),
+                    body: String::new(),
+                }),
+                rewrite: None,
+            },
+        ];
+        let request = |uri: &str| RequestHead {
+            method: "POST".into(),
+            uri: uri.into(),
+            headers: vec![],
+        };
+        let mocked = mock_for(&rules, &request("/hooks/stripe?id=1"));
+        assert_eq!(
+            mocked.map(|(name, mock)| (name, mock.status)),
+            Some(("stripe", 202))
+        );
+        assert!(mock_for(&rules, &request("/hooks/github")).is_none());
+        let github = request("/hooks/github");
+        let names: Vec<_> = rewrites_for(&rules, &github)
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["inject"]);
+    }
+}
@@ -0,0 +1,244 @@
+//! Turn a captured request into something that sends it again from a terminal
+//! or a test.
+//!
+//! Snippets target the local service behind the tunnel directly, with the
+//! headers the service originally saw, so they reproduce what it received.
+use std::{fmt, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::RequestCycle;
+
+/// Headers http clients work out themselves from the body they send, snippets
+/// and replays leave them out
+pub const SKIPPED_HEADERS: &[&str] = &["content-length", "transfer-encoding", "connection"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnippetFormat {
+    Curl,
+    Httpie,
+    Fetch,
+    Reqwest,
+}
+
+impl SnippetFormat {
+    pub const ALL: [SnippetFormat; 4] = [
+        SnippetFormat::Curl,
+        SnippetFormat::Httpie,
+        SnippetFormat::Fetch,
+        SnippetFormat::Reqwest,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SnippetFormat::Curl => "curl",
+            SnippetFormat::Httpie => "httpie",
+            SnippetFormat::Fetch => "fetch",
+            SnippetFormat::Reqwest => "reqwest",
+        }
+    }
+
+    /// Source that sends the request of `cycle` to the local service again
+    pub fn render(&self, cycle: &RequestCycle) -> String {
+        let url = cycle.ports.target_url(&cycle.request_head.uri);
+        let method = cycle.request_head.method.as_str();
+        let headers: Vec<(&str, &str)> = cycle
+            .request_head
+            .headers
+            .iter()
+            .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let body = &cycle.request_body[..];
+        match self {
+            SnippetFormat::Curl => curl(method, &url, &headers, body),
+            SnippetFormat::Httpie => httpie(method, &url, &headers, body),
+            SnippetFormat::Fetch => fetch(method, &url, &headers, body),
+            SnippetFormat::Reqwest => reqwest(method, &url, &headers, body),
+        }
+    }
+}
+
+impl fmt::Display for SnippetFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SnippetFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SnippetFormat::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!("unknown snippet format '{s}', expected curl, httpie, fetch or reqwest")
+            })
+    }
+}
+
+/// Quote for a posix shell, nothing is special inside single quotes except the quote itself
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Binary bodies can not be put on a command line, they are piped in from base64 instead
+fn piped_body(body: &[u8]) -> String {
+    format!("echo {} | base64 -d | ", STANDARD.encode(body))
+}
+
+fn curl(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> String {
+    let mut args = vec![format!("curl -X {method} {}", shell_quote(url))];
+    args.extend(
+        headers
+            .iter()
+            .map(|(name, value)| format!("-H {}", shell_quote(&format!("{name}: {value}")))),
+    );
+    let mut prefix = String::new();
+    match std::str::from_utf8(body) {
+        _ if body.is_empty() => {}
+        Ok(text) => args.push(format!("--data-raw {}", shell_quote(text))),
+        Err(_) => {
+            prefix = piped_body(body);
+            args.push("--data-binary @-".into());
+        }
+    }
+    format!("{prefix}{}", args.join(" \\\n  "))
+}
+
+fn httpie(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> String {
+    let mut args = vec![format!("http {method} {}", shell_quote(url))];
+    args.extend(
+        headers
+            .iter()
+            .map(|(name, value)| shell_quote(&format!("{name}:{value}"))),
+    );
+    let mut prefix = String::new();
+    match std::str::from_utf8(body) {
+        _ if body.is_empty() => {}
+        Ok(text) => args.push(format!("--raw {}", shell_quote(text))),
+        // httpie reads the body from stdin when something is piped in
+        Err(_) => prefix = piped_body(body),
+    }
+    format!("{prefix}{}", args.join(" \\\n  "))
+}
+
+/// Json strings are valid javascript strings
+fn js_string(value: &str) -> String {
+    serde_json::to_string(value).expect("strings always serialize")
+}
+
+fn fetch(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> String {
+    // A list of pairs rather than an object, so repeated headers survive
+    let headers: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| format!("    [{}, {}],", js_string(name), js_string(value)))
+        .collect();
+    let body = match std::str::from_utf8(body) {
+        _ if body.is_empty() => String::new(),
+        Ok(text) => format!("  body: {},\n", js_string(text)),
+        Err(_) => format!(
+            "  body: Uint8Array.from(atob({}), (c) => c.charCodeAt(0)),\n",
+            js_string(&STANDARD.encode(body))
+        ),
+    };
+    format!(
+        "await fetch({}, {{\n  method: {},\n  headers: [\n{}\n  ],\n{body}}});",
+        js_string(url),
+        js_string(method),
+        headers.join("\n"),
+    )
+}
+
+fn reqwest(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> String {
+    // Debug formatting of a str is a valid rust string literal
+    let mut lines = vec![
+        "let response = reqwest::Client::new()".to_owned(),
+        format!("    .request({method:?}.parse()?, {url:?})"),
+    ];
+    lines.extend(
+        headers
+            .iter()
+            .map(|(name, value)| format!("    .header({name:?}, {value:?})")),
+    );
+    match std::str::from_utf8(body) {
+        _ if body.is_empty() => {}
+        Ok(text) => lines.push(format!("    .body({text:?})")),
+        Err(_) => lines.push(format!("    .body(vec!{body:?})")),
+    }
+    lines.push("    .send()".into());
+    lines.push("    .await?;".into());
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestHead, TunnelPorts};
+
+    fn cycle(body: &[u8]) -> RequestCycle {
+        RequestCycle {
+            request_head: RequestHead {
+                method: "POST".into(),
+                uri: "/hooks?source=it's".into(),
+                headers: vec![
+                    ("content-type".into(), "application/json".into()),
+                    ("content-length".into(), body.len().to_string()),
+                ],
+            },
+            request_body: body.to_vec(),
+            ports: TunnelPorts {
+                target_port: 3000,
+                target_host: "localhost".into(),
+                ..TunnelPorts::default()
+            },
+            ..RequestCycle::default()
+        }
+    }
+
+    #[test]
+    fn shell_snippets_quote_everything() {
+        let cycle = cycle(br#"{"note": "it's \"fine\""}"#);
+        assert_eq!(
+            SnippetFormat::Curl.render(&cycle),
+            r#"curl -X POST 'http://localhost:3000/hooks?source=it'\''s' \
+  -H 'content-type: application/json' \
+  --data-raw '{"note": "it'\''s \"fine\""}'"#
+        );
+        assert_eq!(
+            SnippetFormat::Httpie.render(&cycle),
+            r#"http POST 'http://localhost:3000/hooks?source=it'\''s' \
+  'content-type:application/json' \
+  --raw '{"note": "it'\''s \"fine\""}'"#
+        );
+    }
+
+    #[test]
+    fn binary_bodies_are_sent_as_bytes() {
+        let cycle = cycle(&[0xff, 0x00]);
+        assert!(SnippetFormat::Curl
+            .render(&cycle)
+            .starts_with("echo /wA= | base64 -d | curl"));
+        assert!(SnippetFormat::Fetch
+            .render(&cycle)
+            .contains(r#"atob("/wA=")"#));
+        assert!(SnippetFormat::Reqwest
+            .render(&cycle)
+            .contains(".body(vec![255, 0])"));
+        assert_eq!("HTTPie".parse(), Ok(SnippetFormat::Httpie));
+    }
+
+    #[test]
+    fn snippets_go_to_wherever_the_tunnel_forwards() {
+        let mut cycle = cycle(b"");
+        cycle.ports.target_host = "fd00::2".into();
+        cycle.ports.target_port = 443;
+        cycle.ports.target_tls = true;
+        assert!(SnippetFormat::Fetch
+            .render(&cycle)
+            .starts_with(r#"await fetch("https://[fd00::2]/hooks?source=it's""#));
+    }
+}